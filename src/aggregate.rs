@@ -0,0 +1,136 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Importances aggregated over the forests of the iterative run
+//! Every forest adds the importance of each variant active while it grew (0 if it never split on it),
+//! and the per-tree importances count how often the variant was picked across all trees
+
+use crate::forest;
+use crate::statistics;
+use crate::variants;
+
+use rand::{Rng, thread_rng};
+
+use std::collections::HashMap;
+
+/// Importances of one variant over the forests it was active in
+#[derive(Default)]
+struct VariantRecord {
+    forest_importances: Vec<f64>,
+    trees_active: usize, // trees grown while the variant was active
+    trees: forest::TreeImportance
+}
+
+/// Summary of the importances of a variant
+/// forest_frequency is the fraction of forests that split on the variant, tree_frequency the fraction
+/// of trees, tree_mean and tree_sd the importance per tree (0 for trees not splitting on it)
+pub struct Summary {
+    pub var: usize,
+    pub n_forests: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub sd: f64,
+    pub ci_low: f64,
+    pub ci_high: f64,
+    pub max: f64,
+    pub forest_frequency: f64,
+    pub tree_frequency: f64,
+    pub tree_mean: f64,
+    pub tree_sd: f64
+}
+
+#[derive(Default)]
+pub struct Aggregate {
+    records: HashMap<usize, VariantRecord>
+}
+
+impl Aggregate {
+
+    pub fn new() -> Self {
+        Aggregate::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Add the importances of a grown forest for the active variants
+    pub fn add_forest(&mut self, f: &forest::Forest, active: &[usize]) {
+        let importances = f.get_var_importances();
+        let tree_importances = f.get_tree_importances();
+        for v in active {
+            let record = self.records.entry(*v).or_default();
+            record.forest_importances.push(*importances.get(v).unwrap_or(&0.));
+            record.trees_active += f.n_trees();
+            if let Some(t) = tree_importances.get(v) {
+                record.trees.n_trees += t.n_trees;
+                record.trees.sum += t.sum;
+                record.trees.sum_sq += t.sum_sq;
+            }
+        }
+    }
+
+    /// Summaries of all variants, by decreasing mean importance
+    /// The confidence interval is a percentile bootstrap of the mean over the forests, so it only reflects
+    /// the forest to forest variation: it collapses to the mean with a single forest and is unreliable with few
+    pub fn summarize(&self, n_bootstrap: usize, level: f64) -> Vec<Summary> {
+        let mut rng = thread_rng();
+        let mut summaries: Vec<Summary> = self.records.iter().map(|(v, r)| {
+            let x = &r.forest_importances;
+            let n = x.len() as f64;
+            let mean = x.iter().sum::<f64>() / n;
+            let sd = match x.len() {
+                0 | 1 => 0.,
+                _ => (x.iter().map(|xi| (xi - mean) * (xi - mean)).sum::<f64>() / (n - 1.)).sqrt()
+            };
+            let mut boot_means: Vec<f64> = (0..n_bootstrap)
+                .map(|_| (0..x.len()).map(|_| x[rng.gen_range(0..x.len())]).sum::<f64>() / n)
+                .collect();
+            boot_means.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            let (ci_low, ci_high) = match boot_means.is_empty() {
+                true => (mean, mean),
+                false => (statistics::quantile(&boot_means, (1. - level) / 2.), statistics::quantile(&boot_means, (1. + level) / 2.))
+            };
+            let n_trees = r.trees_active.max(1) as f64;
+            let tree_mean = r.trees.sum / n_trees;
+            Summary {
+                var: *v,
+                n_forests: x.len(),
+                mean,
+                median: statistics::median(x),
+                sd,
+                ci_low,
+                ci_high,
+                max: x.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                forest_frequency: x.iter().filter(|xi| **xi != 0.).count() as f64 / n,
+                tree_frequency: r.trees.n_trees as f64 / n_trees,
+                tree_mean,
+                tree_sd: (r.trees.sum_sq / n_trees - tree_mean * tree_mean).max(0.).sqrt()
+            }
+        }).collect();
+        summaries.sort_by(|a, b| b.mean.partial_cmp(&a.mean).unwrap_or(std::cmp::Ordering::Equal).then(a.var.cmp(&b.var)));
+        summaries
+    }
+}
+
+/// Keep the highest forest importance of each summarized variant
+pub fn update_variants(summaries: &[Summary], variants: &mut [variants::Variant]) {
+    for s in summaries {
+        variants[s.var].set_importance(s.max);
+    }
+}
+
+/// print the aggregated importances to stdout
+pub fn print_summaries(summaries: &[Summary], variants: &[variants::Variant], level: f64) {
+    // rounded so levels like 0.29 are not printed as 28.999999999999996
+    let pct = (level * 1e8).round() / 1e6;
+    println!("#AGGREGATE_IMPORTANCE");
+    println!("#id\tn_forests\tmean\tmedian\tsd\tci{}_low\tci{}_high\tmax\tforest_frequency\ttree_frequency\ttree_mean\ttree_sd\t{}",
+        pct, pct, variants::DESCRIBE_HEADER);
+    for s in summaries {
+        let v = &variants[s.var];
+        println!("{}\t{}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{}",
+            v.id, s.n_forests, s.mean, s.median, s.sd, s.ci_low, s.ci_high, v.max_importance,
+            s.forest_frequency, s.tree_frequency, s.tree_mean, s.tree_sd, v.describe());
+    }
+}
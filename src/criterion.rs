@@ -0,0 +1,350 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Split criteria
+//! Scores a candidate split of the subjects in a node into genotype 0 (left) and genotype 1 (right)
+
+use crate::matrix;
+use crate::tree;
+
+/// Criterion selectable per run
+/// Gini, Entropy, ChiSquare and GTest for binary and categorical outcomes,
+/// Sdr, Mse and Mad for continuous outcomes and LogRank for survival outcomes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Criterion {
+    Gini,
+    Entropy,
+    ChiSquare,
+    GTest,
+    Sdr,
+    Mse,
+    Mad,
+    LogRank
+}
+
+/// A split criterion
+/// score takes the phenotypes (times if survival), the events (empty unless survival) and the genotypes of a node
+pub trait SplitCriterion: Send + Sync {
+
+    fn score(&self, p: &[&f64], e: &[&f64], g: &[&u8]) -> f64;
+
+    /// Whether lower scores are better (weighted child impurity) rather than higher (reductions and test statistics)
+    fn minimize(&self) -> bool {
+        false
+    }
+
+    /// Decrease achieved by a split with the given score, compared against the min impurity decrease
+    fn decrease(&self, _p: &[&f64], score: f64) -> f64 {
+        score
+    }
+}
+
+pub struct Gini;
+pub struct Entropy { pub n_classes: usize }
+pub struct ChiSquare { pub n_classes: usize }
+pub struct GTest { pub n_classes: usize }
+pub struct Sdr;
+pub struct Mse;
+pub struct Mad;
+pub struct LogRank;
+
+impl Criterion {
+
+    /// Parse the criterion name given on the command line
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "gini" => Ok(Criterion::Gini),
+            "entropy" => Ok(Criterion::Entropy),
+            "chi-square" => Ok(Criterion::ChiSquare),
+            "g-test" => Ok(Criterion::GTest),
+            "sdr" => Ok(Criterion::Sdr),
+            "mse" => Ok(Criterion::Mse),
+            "mad" => Ok(Criterion::Mad),
+            "log-rank" => Ok(Criterion::LogRank),
+            _ => Err(format!("unknown split criterion {}", name))
+        }
+    }
+
+    /// Name of the criterion on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Criterion::Gini => "gini",
+            Criterion::Entropy => "entropy",
+            Criterion::ChiSquare => "chi-square",
+            Criterion::GTest => "g-test",
+            Criterion::Sdr => "sdr",
+            Criterion::Mse => "mse",
+            Criterion::Mad => "mad",
+            Criterion::LogRank => "log-rank"
+        }
+    }
+
+    /// Criterion used when none is requested
+    pub fn default_for(outcome: matrix::Outcome) -> Self {
+        match outcome {
+            matrix::Outcome::Continuous => Criterion::Sdr,
+            matrix::Outcome::Survival => Criterion::LogRank,
+            _ => Criterion::Gini
+        }
+    }
+
+    /// Check that the criterion fits the outcome type
+    pub fn check(&self, outcome: matrix::Outcome) -> Result<(), String> {
+        match (self, outcome) {
+            (Criterion::Gini | Criterion::Entropy | Criterion::ChiSquare | Criterion::GTest, matrix::Outcome::Binary | matrix::Outcome::Categorical) => Ok(()),
+            (Criterion::Sdr | Criterion::Mse | Criterion::Mad, matrix::Outcome::Continuous) => Ok(()),
+            (Criterion::LogRank, matrix::Outcome::Survival) => Ok(()),
+            _ => Err(format!("split criterion {:?} does not fit a {:?} outcome", self, outcome))
+        }
+    }
+
+    pub fn make(&self, n_classes: usize) -> Box<dyn SplitCriterion> {
+        match self {
+            Criterion::Gini => Box::new(Gini),
+            Criterion::Entropy => Box::new(Entropy { n_classes }),
+            Criterion::ChiSquare => Box::new(ChiSquare { n_classes }),
+            Criterion::GTest => Box::new(GTest { n_classes }),
+            Criterion::Sdr => Box::new(Sdr),
+            Criterion::Mse => Box::new(Mse),
+            Criterion::Mad => Box::new(Mad),
+            Criterion::LogRank => Box::new(LogRank)
+        }
+    }
+}
+
+impl SplitCriterion for Gini {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        tree::calc_gini(p, g)
+    }
+
+    fn minimize(&self) -> bool {
+        true
+    }
+
+    fn decrease(&self, p: &[&f64], score: f64) -> f64 {
+        let counts = count_classes(p.iter().map(|x| **x), 0);
+        let n: f64 = counts.iter().sum::<f64>().max(1.);
+        (1. - counts.iter().map(|c| (c / n).powi(2)).sum::<f64>()) - score
+    }
+}
+
+/// Weighted entropy of the child nodes, normalized by log2 of the number of classes so it stays within 0-1
+impl SplitCriterion for Entropy {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        let (g0, g1) = split_groups(p, g);
+        if g0.is_empty() || g1.is_empty() {
+            return 1.
+        }
+        let (n0, n1) = (g0.len() as f64, g1.len() as f64);
+        let h0 = entropy(&count_classes(g0, self.n_classes));
+        let h1 = entropy(&count_classes(g1, self.n_classes));
+        (n0 * h0 + n1 * h1) / ((n0 + n1) * max_entropy(self.n_classes))
+    }
+
+    fn minimize(&self) -> bool {
+        true
+    }
+
+    fn decrease(&self, p: &[&f64], score: f64) -> f64 {
+        entropy(&count_classes(p.iter().map(|x| **x), self.n_classes)) / max_entropy(self.n_classes) - score
+    }
+}
+
+/// Pearson chi-square statistic of the genotype by class table
+impl SplitCriterion for ChiSquare {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        contingency_statistic(p, g, self.n_classes, |o, e| (o - e) * (o - e) / e)
+    }
+}
+
+/// G-test (likelihood ratio) statistic of the genotype by class table
+impl SplitCriterion for GTest {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        contingency_statistic(p, g, self.n_classes, |o, e| if o > 0. { 2. * o * (o / e).ln() } else { 0. })
+    }
+}
+
+/// Standard deviation reduction
+impl SplitCriterion for Sdr {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        tree::calc_sdr(p, g)
+    }
+}
+
+/// Reduction of the mean squared error (variance) around the node means
+impl SplitCriterion for Mse {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        deviation_reduction(p, g, |v| {
+            let mean = v.iter().sum::<f64>() / v.len() as f64;
+            v.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / v.len() as f64
+        })
+    }
+}
+
+/// Reduction of the mean absolute deviation around the node medians
+impl SplitCriterion for Mad {
+
+    fn score(&self, p: &[&f64], _e: &[&f64], g: &[&u8]) -> f64 {
+        deviation_reduction(p, g, |v| {
+            let mut sorted = v.to_vec();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let median = match sorted.len() % 2 {
+                0 => (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.,
+                _ => sorted[sorted.len() / 2]
+            };
+            v.iter().map(|x| (x - median).abs()).sum::<f64>() / v.len() as f64
+        })
+    }
+}
+
+/// Log-rank test statistic
+impl SplitCriterion for LogRank {
+
+    fn score(&self, p: &[&f64], e: &[&f64], g: &[&u8]) -> f64 {
+        tree::calc_logrank(p, e, g)
+    }
+}
+
+/// Phenotypes of the genotype 0 and genotype 1 subjects, missing genotypes are left out
+fn split_groups(p: &[&f64], g: &[&u8]) -> (Vec<f64>, Vec<f64>) {
+    let mut g0: Vec<f64> = Vec::new();
+    let mut g1: Vec<f64> = Vec::new();
+    for (pi, gi) in p.iter().zip(g.iter()) {
+        match **gi {
+            0 => g0.push(**pi),
+            1 => g1.push(**pi),
+            matrix::MISSING_GENOTYPE => (),
+            _ => panic!("variable mismatch?, {}, {}", pi, gi),
+        }
+    }
+    (g0, g1)
+}
+
+/// Count the subjects in each class, the counts grow to fit the largest class label seen
+fn count_classes(p: impl IntoIterator<Item = f64>, n_classes: usize) -> Vec<f64> {
+    let mut counts: Vec<f64> = vec![0.; n_classes];
+    for pi in p {
+        let class = pi as usize;
+        if counts.len() <= class {
+            counts.resize(class + 1, 0.);
+        }
+        counts[class] += 1.;
+    }
+    counts
+}
+
+/// Shannon entropy (bits) of class counts
+fn entropy(counts: &[f64]) -> f64 {
+    let n: f64 = counts.iter().sum();
+    if n == 0. {
+        return 0.
+    }
+    counts.iter().filter(|c| **c > 0.).map(|c| -(c / n) * (c / n).log2()).sum()
+}
+
+fn max_entropy(n_classes: usize) -> f64 {
+    (n_classes.max(2) as f64).log2()
+}
+
+/// Sum a cell statistic over the 2 x k table of genotype group by class
+/// Returns 0 when either genotype group is empty
+fn contingency_statistic(p: &[&f64], g: &[&u8], n_classes: usize, cell: impl Fn(f64, f64) -> f64) -> f64 {
+    let (g0, g1) = split_groups(p, g);
+    if g0.is_empty() || g1.is_empty() {
+        return 0.
+    }
+    let c0 = count_classes(g0, n_classes);
+    let c1 = count_classes(g1, n_classes);
+    let (n0, n1): (f64, f64) = (c0.iter().sum(), c1.iter().sum());
+    let n = n0 + n1;
+    let mut stat: f64 = 0.;
+    for k in 0..c0.len().max(c1.len()) {
+        let (o0, o1) = (*c0.get(k).unwrap_or(&0.), *c1.get(k).unwrap_or(&0.));
+        let class_total = o0 + o1;
+        if class_total == 0. {
+            continue
+        }
+        stat += cell(o0, class_total * n0 / n) + cell(o1, class_total * n1 / n);
+    }
+    stat
+}
+
+/// Reduction of a deviation measure from the node to the size-weighted child nodes
+/// Only subjects with a called genotype are counted, returns 0 when either group is empty
+fn deviation_reduction(p: &[&f64], g: &[&u8], deviation: impl Fn(&[f64]) -> f64) -> f64 {
+    let (g0, g1) = split_groups(p, g);
+    if g0.is_empty() || g1.is_empty() {
+        return 0.
+    }
+    let (n0, n1) = (g0.len() as f64, g1.len() as f64);
+    let called: Vec<f64> = g0.iter().chain(g1.iter()).copied().collect();
+    let reduction = deviation(&called) - (n0 * deviation(&g0) + n1 * deviation(&g1)) / (n0 + n1);
+    reduction.max(0.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn refs<T>(x: &[T]) -> Vec<&T> {
+        x.iter().collect()
+    }
+
+    #[test]
+    fn class_criteria() {
+        // genotype 0 has classes (0, 0, 1), genotype 1 has (1, 1, 0), the missing call is left out
+        let p = [0., 0., 1., 1., 1., 0., 1.];
+        let g: [u8; 7] = [0, 0, 0, 1, 1, 1, matrix::MISSING_GENOTYPE];
+        let (p, g) = (refs(&p), refs(&g));
+        let h = -(1. / 3f64) * (1. / 3f64).log2() - (2. / 3f64) * (2. / 3f64).log2();
+        let entropy = Entropy { n_classes: 2 };
+        assert!(close(entropy.score(&p, &[], &g), h));
+        assert!(close(entropy.decrease(&p[..6], h), 1. - h));
+        assert!(close(Gini.score(&p, &[], &g), 4. / 9.));
+        assert!(close(Gini.decrease(&p[..6], 4. / 9.), 1. / 18.));
+        // expected counts are 1.5 in every cell
+        assert!(close(ChiSquare { n_classes: 2 }.score(&p, &[], &g), 4. * 0.25 / 1.5));
+        assert!(close(GTest { n_classes: 2 }.score(&p, &[], &g), 2. * (4. * (2f64 / 1.5).ln() + 2. * (1f64 / 1.5).ln())));
+    }
+
+    #[test]
+    fn class_criteria_direction() {
+        // a perfect split must beat the mixed one in the direction of the criterion
+        let p = [0., 0., 0., 1., 1., 1.];
+        let (mixed, perfect): ([u8; 6], [u8; 6]) = ([0, 1, 0, 1, 0, 1], [0, 0, 0, 1, 1, 1]);
+        for c in [Criterion::Gini, Criterion::Entropy, Criterion::ChiSquare, Criterion::GTest] {
+            let c = c.make(2);
+            let (s_mixed, s_perfect) = (c.score(&refs(&p), &[], &refs(&mixed)), c.score(&refs(&p), &[], &refs(&perfect)));
+            assert!(if c.minimize() { s_perfect < s_mixed } else { s_perfect > s_mixed });
+        }
+        // no split when a genotype group is empty: worst impurity, no association
+        let single: [u8; 6] = [0; 6];
+        assert!(close(Entropy { n_classes: 2 }.score(&refs(&p), &[], &refs(&single)), 1.));
+        assert!(close(ChiSquare { n_classes: 2 }.score(&refs(&p), &[], &refs(&single)), 0.));
+    }
+
+    #[test]
+    fn continuous_criteria() {
+        let p = [1., 2., 3., 10., 11., 12.];
+        let g: [u8; 6] = [0, 0, 0, 1, 1, 1];
+        let (p, g) = (refs(&p), refs(&g));
+        // node variance 125.5 / 6 and median absolute deviation 4.5, 2/3 in both children
+        let mse = Mse.score(&p, &[], &g);
+        assert!(close(mse, 125.5 / 6. - 2. / 3.));
+        assert!(close(Mse.decrease(&p, mse), mse));
+        assert!(close(Mad.score(&p, &[], &g), 4.5 - 2. / 3.));
+        assert!(!Mse.minimize() && !Mad.minimize());
+        let mixed: [u8; 6] = [0, 1, 0, 1, 0, 1];
+        assert!(Mse.score(&p, &[], &refs(&mixed)) < mse);
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Cross-validation of the forest
+//! Each fold grows the selection and iterative forests on the training subjects only,
+//! then the final forest predicts the held-out subjects
+
+use crate::forest;
+use crate::matrix;
+use crate::stability;
+use crate::statistics;
+use crate::utils;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Number of top variants compared between folds for the importance overlap
+const STABILITY_TOP: usize = 20;
+
+/// Settings of a full run: the selection forest, the z score cut and the iterative forests
+#[derive(Clone, Copy)]
+pub struct RunSettings {
+    pub hp: forest::HyperParameters,
+    pub hp2: forest::HyperParameters,
+    pub z_keep: f64,
+    pub n_iter: usize
+}
+
+/// Held-out metrics and final importances of one fold
+pub struct FoldResult {
+    pub n_train: usize,
+    pub n_test: usize,
+    pub metrics: Vec<(&'static str, f64)>,
+    pub importances: HashMap<usize, f64>
+}
+
+/// Split the active subjects into k folds at random
+/// Stratified folds deal each class (binary and categorical) or event status (survival) out evenly,
+/// continuous outcomes are stratified by their rank
+pub fn make_folds(gm: &matrix::GenoMatrix, k: usize, stratified: bool) -> Vec<Vec<usize>> {
+    let mut rng = thread_rng();
+    let mut subjects: Vec<usize> = gm.sample_indices().to_vec();
+    subjects.shuffle(&mut rng);
+    if stratified {
+        // a stable sort keeps the random order within each stratum
+        match gm.outcome {
+            matrix::Outcome::Survival => subjects.sort_by(|a, b| gm.events[*a].partial_cmp(&gm.events[*b]).unwrap()),
+            _ => subjects.sort_by(|a, b| gm.response()[*a].partial_cmp(&gm.response()[*b]).unwrap())
+        }
+    }
+    let mut folds: Vec<Vec<usize>> = vec![Vec::new(); k];
+    for (i, s) in subjects.into_iter().enumerate() {
+        folds[i % k].push(s);
+    }
+    folds
+}
+
+/// Grow the selection forest, keep the variants at or above z_keep and grow the iterative forests
+/// Only the final forest keeps its trees for prediction (and its OOB predictions with track_oob),
+/// the active variants of the matrix are restored afterwards
+pub fn fit(gm: &mut matrix::GenoMatrix, settings: &RunSettings, track_oob: bool) -> Result<forest::Forest, io::Error> {
+    let variants = gm.genotype_indices().to_vec();
+    let mut f = forest::Forest::new(settings.hp, settings.n_iter == 0);
+    f.set_track_oob(track_oob && settings.n_iter == 0);
+    f.grow(gm)?;
+    gm.set_genotype_indices(f.keep_vars(settings.z_keep));
+    for n in 1..=settings.n_iter {
+        f = forest::Forest::new(settings.hp2, n == settings.n_iter);
+        f.set_track_oob(track_oob && n == settings.n_iter);
+        f.grow(gm)?;
+    }
+    gm.set_genotype_indices(variants);
+    Ok(f)
+}
+
+/// Run k-fold cross-validation, the active subjects of the matrix are restored afterwards
+/// A residualized phenotype is fitted again on the training subjects of each fold, so the held-out
+/// subjects are scored on residuals from a fit they were not part of
+pub fn cross_validate(gm: &mut matrix::GenoMatrix, settings: &RunSettings, folds: &[Vec<usize>]) -> Result<Vec<FoldResult>, io::Error> {
+    let refit = |gm: &mut matrix::GenoMatrix| gm.refit_residuals().map_err(io::Error::other);
+    let subjects = gm.sample_indices().to_vec();
+    let mut results: Vec<FoldResult> = Vec::new();
+    for (i, test) in folds.iter().enumerate() {
+        eprintln!("Cross-validation fold {} of {}", i + 1, folds.len());
+        let held_out: HashSet<usize> = test.iter().copied().collect();
+        let train: Vec<usize> = subjects.iter().filter(|s| !held_out.contains(s)).copied().collect();
+        let n_train = train.len();
+        gm.set_sample_indices(train);
+        let fitted = refit(gm).and_then(|_| fit(gm, settings, false));
+        let scored = fitted.map(|f| {
+            let predictions = f.predict(gm, test);
+            (evaluate(gm, test, &predictions), f.get_var_importances())
+        });
+        gm.set_sample_indices(subjects.to_vec());
+        refit(gm)?;
+        let (metrics, importances) = scored?;
+        results.push(FoldResult {
+            n_train,
+            n_test: test.len(),
+            metrics,
+            importances
+        });
+    }
+    Ok(results)
+}
+
+/// Prediction metrics over the subjects with a prediction
+/// Binary: AUC and accuracy, categorical: accuracy, continuous: R2 and MSE, survival: C-index
+pub fn evaluate(gm: &matrix::GenoMatrix, subjects: &[usize], predictions: &[Vec<f64>]) -> Vec<(&'static str, f64)> {
+    let predicted: Vec<(usize, &Vec<f64>)> = subjects.iter().zip(predictions.iter())
+        .filter(|(_, p)| !p.is_empty())
+        .map(|(s, p)| (*s, p))
+        .collect();
+    let observed: Vec<f64> = predicted.iter().map(|(s, _)| gm.response()[*s]).collect();
+    match gm.outcome {
+        matrix::Outcome::Continuous => {
+            let values: Vec<f64> = predicted.iter().map(|(_, p)| p[0]).collect();
+            vec![("r2", statistics::r_squared(&observed, &values)), ("mse", statistics::mean_squared_error(&observed, &values))]
+        },
+        matrix::Outcome::Survival => {
+            let risk: Vec<f64> = predicted.iter().map(|(_, p)| p.iter().sum()).collect();
+            let events: Vec<f64> = predicted.iter().map(|(s, _)| gm.events[*s]).collect();
+            vec![("c_index", statistics::concordance_index(&observed, &events, &risk))]
+        },
+        outcome => {
+            let classes: Vec<f64> = predicted.iter().map(|(_, p)| utils::argmax(p) as f64).collect();
+            let accuracy = 1. - statistics::misclassification_rate(&observed, &classes);
+            match outcome {
+                matrix::Outcome::Binary => {
+                    let scores: Vec<f64> = predicted.iter().map(|(_, p)| p[1]).collect();
+                    vec![("auc", statistics::auc(&observed, &scores)), ("accuracy", accuracy)]
+                },
+                _ => vec![("accuracy", accuracy)]
+            }
+        }
+    }
+}
+
+/// Agreement of the importances between each pair of folds
+/// Returns the mean Spearman correlation (variants missing from a fold count as 0) and the mean
+/// Jaccard overlap of the top variants, NaN when there is nothing to compare
+pub fn importance_stability(results: &[FoldResult]) -> (f64, f64) {
+    let all_vars: Vec<usize> = results.iter()
+        .flat_map(|r| r.importances.keys().copied())
+        .collect::<HashSet<usize>>()
+        .into_iter()
+        .collect();
+    let ranked: Vec<Vec<f64>> = results.iter()
+        .map(|r| statistics::ranks(&all_vars.iter().map(|v| *r.importances.get(v).unwrap_or(&0.)).collect::<Vec<f64>>()))
+        .collect();
+    let tops: Vec<HashSet<usize>> = results.iter().map(|r| stability::top_variants(&r.importances, STABILITY_TOP)).collect();
+    // a fold whose importances are all tied has no ranking to compare, the correlation is left out
+    let ranking: Vec<bool> = ranked.iter().map(|r| r.iter().any(|x| *x != r[0])).collect();
+    let (mut rho, mut n_rho, mut jaccard, mut n_pairs) = (0., 0., 0., 0.);
+    for i in 0..results.len() {
+        for j in i + 1..results.len() {
+            if ranking[i] && ranking[j] {
+                rho += statistics::correlation(&ranked[i], &ranked[j]);
+                n_rho += 1.;
+            }
+            let union = tops[i].union(&tops[j]).count();
+            if union > 0 {
+                jaccard += tops[i].intersection(&tops[j]).count() as f64 / union as f64;
+            }
+            n_pairs += 1.;
+        }
+    }
+    let rho = if n_rho > 0. { rho / n_rho } else { f64::NAN };
+    let jaccard = if n_pairs > 0. { jaccard / n_pairs } else { f64::NAN };
+    (rho, jaccard)
+}
+
+/// Mean of each metric over the folds
+pub fn mean_metrics(results: &[FoldResult]) -> Vec<(&'static str, f64)> {
+    match results.first() {
+        None => Vec::new(),
+        Some(first) => first.metrics.iter().enumerate().map(|(m, (name, _))| {
+            let values: Vec<f64> = results.iter().map(|r| r.metrics[m].1).filter(|v| v.is_finite()).collect();
+            (*name, values.iter().sum::<f64>() / values.len().max(1) as f64)
+        }).collect()
+    }
+}
+
+/// print the per-fold metrics, their means and the importance stability to stdout
+pub fn print_results(results: &[FoldResult]) {
+    println!("#CROSS_VALIDATION");
+    let names: Vec<&str> = results.first().map_or(Vec::new(), |r| r.metrics.iter().map(|(n, _)| *n).collect());
+    println!("#fold\tn_train\tn_test\t{}", names.join("\t"));
+    for (i, r) in results.iter().enumerate() {
+        let values: Vec<String> = r.metrics.iter().map(|(_, v)| format!("{:?}", v)).collect();
+        println!("{}\t{}\t{}\t{}", i + 1, r.n_train, r.n_test, values.join("\t"));
+    }
+    let means: Vec<String> = mean_metrics(results).iter().map(|(_, v)| format!("{:?}", v)).collect();
+    println!("mean\t\t\t{}", means.join("\t"));
+    let (rho, jaccard) = importance_stability(results);
+    println!("#IMPORTANCE_STABILITY");
+    println!("#mean_spearman\tmean_top{}_jaccard", STABILITY_TOP);
+    println!("{:?}\t{:?}", rho, jaccard);
+}
@@ -0,0 +1,113 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Export of grown trees
+//! Nested JSON for downstream tools and Graphviz DOT for visual inspection
+//! Nodes are numbered in pre-order within each tree, so every node has its own ID
+
+use crate::tree;
+use crate::variants;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Write the trees as a JSON array, one nested object per tree
+pub fn write_trees_json(path: &str, trees: &[tree::Node], variants: &[variants::Variant]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "[")?;
+    for (i, t) in trees.iter().enumerate() {
+        let mut next_id: usize = 0;
+        let sep = if i + 1 < trees.len() { "," } else { "" };
+        writeln!(w, "{{\"tree\": {}, \"root\": {}}}{}", i, node_json(t, variants, &mut next_id), sep)?;
+    }
+    writeln!(w, "]")?;
+    w.flush()
+}
+
+/// Write the trees as Graphviz DOT, one digraph per tree (render with e.g. dot -Tpdf)
+/// Splits that scored better on shuffled phenotypes (negative importance) are drawn dashed
+pub fn write_trees_dot(path: &str, trees: &[tree::Node], variants: &[variants::Variant]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    for (i, t) in trees.iter().enumerate() {
+        writeln!(w, "digraph tree_{} {{", i)?;
+        writeln!(w, "  node [shape=box];")?;
+        let mut next_id: usize = 0;
+        node_dot(&mut w, t, variants, &mut next_id)?;
+        writeln!(w, "}}")?;
+    }
+    w.flush()
+}
+
+/// Nested JSON object of a node and its children
+fn node_json(node: &tree::Node, variants: &[variants::Variant], next_id: &mut usize) -> String {
+    let id = *next_id;
+    *next_id += 1;
+    let value: Vec<String> = node.value.iter().map(|v| json_number(*v)).collect();
+    if node.is_empty {
+        return format!("{{\"id\": {}, \"leaf\": true, \"n\": {}, \"value\": [{}]}}", id, node.node_n, value.join(", "))
+    }
+    let mut fields = vec![
+        format!("\"id\": {}", id),
+        String::from("\"leaf\": false"),
+        format!("\"variant\": {}", json_string(&variants[node.var].id)),
+        format!("\"variant_index\": {}", node.var),
+        format!("\"rule\": {}", json_string(&format!("genotype <= {}", node.threshold))),
+        format!("\"n\": {}", node.node_n),
+        format!("\"score\": {}", json_number(node.score)),
+        format!("\"negative\": {}", node.neg),
+        format!("\"value\": [{}]", value.join(", "))
+    ];
+    if let Some(l) = &node.left {
+        fields.push(format!("\"left\": {}", node_json(l, variants, next_id)));
+    }
+    if let Some(r) = &node.right {
+        fields.push(format!("\"right\": {}", node_json(r, variants, next_id)));
+    }
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// DOT statements of a node, its children and the edges to them, returns the node's ID
+fn node_dot<W: Write>(w: &mut W, node: &tree::Node, variants: &[variants::Variant], next_id: &mut usize) -> io::Result<usize> {
+    let id = *next_id;
+    *next_id += 1;
+    let value: Vec<String> = node.value.iter().map(|v| format!("{:.3}", v)).collect();
+    if node.is_empty {
+        writeln!(w, "  n{} [label=\"leaf\\nn={}\\nvalue=[{}]\", style=rounded];", id, node.node_n, value.join(", "))?;
+        return Ok(id)
+    }
+    let style = if node.neg { ", style=dashed" } else { "" };
+    writeln!(w, "  n{} [label=\"{}\\ngenotype <= {}\\nn={}\\nscore={:.4}\"{}];",
+        id, dot_escape(&variants[node.var].id), node.threshold, node.node_n, node.score, style)?;
+    if let Some(l) = &node.left {
+        let child = node_dot(w, l, variants, next_id)?;
+        writeln!(w, "  n{} -> n{} [label=\"<= {}\"];", id, child, node.threshold)?;
+    }
+    if let Some(r) = &node.right {
+        let child = node_dot(w, r, variants, next_id)?;
+        writeln!(w, "  n{} -> n{} [label=\"> {}\"];", id, child, node.threshold)?;
+    }
+    Ok(id)
+}
+
+/// JSON has no NaN or infinity, those are written as null
+fn json_number(x: f64) -> String {
+    if x.is_finite() { format!("{:?}", x) } else { String::from("null") }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,259 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Quality filters applied to the genotype matrix before any trees are grown
+//! Filters only change the active sample and variant sets of the matrix, the data are never re-read
+//! Genotypes coded 0/1 are carrier calls (the coding the trees take), frequencies and counts are then of carriers;
+//! allele frequencies, counts and the Hardy-Weinberg test need 0/1/2 dosages
+
+use crate::matrix;
+use crate::statistics;
+use crate::variants;
+
+use rayon::prelude::*;
+
+use std::collections::HashSet;
+
+/// Filter thresholds, a filter is skipped when its threshold is None
+/// min_maf = minimum minor allele frequency (minor carrier frequency for carrier coding)
+/// min_mac = minimum minor allele count (minor carrier count for carrier coding)
+/// max_variant_missing = maximum fraction of missing calls for a variant
+/// min_hwe_p = minimum Hardy-Weinberg exact test p-value, dosage coding only
+/// remove_monomorphic = drop variants with a single observed genotype
+/// max_sample_missing = maximum fraction of missing calls for a subject
+pub struct FilterOptions {
+    pub min_maf: Option<f64>,
+    pub min_mac: Option<usize>,
+    pub max_variant_missing: Option<f64>,
+    pub min_hwe_p: Option<f64>,
+    pub remove_monomorphic: bool,
+    pub max_sample_missing: Option<f64>
+}
+
+/// Sample and variant subsets, a subset is skipped when it is None
+/// keep_samples / remove_samples = sample IDs to keep / remove
+/// extract / exclude = variant IDs to keep / remove
+/// regions = keep only variants with a position inside one of these regions
+pub struct SubsetOptions {
+    pub keep_samples: Option<HashSet<String>>,
+    pub remove_samples: Option<HashSet<String>>,
+    pub extract: Option<HashSet<String>>,
+    pub exclude: Option<HashSet<String>>,
+    pub regions: Option<Vec<variants::Region>>
+}
+
+/// Number of variants and samples removed by each filter, in the order the filters were applied
+#[derive(Default)]
+pub struct FilterReport {
+    pub rows: Vec<(String, usize, usize)>
+}
+
+/// Genotype counts of a single variant over the active subjects
+#[derive(Debug, PartialEq)]
+pub(crate) struct VariantCounts {
+    hom_ref: usize,
+    het: usize,
+    hom_alt: usize,
+    missing: usize
+}
+
+impl VariantCounts {
+
+    fn called(&self) -> usize {
+        self.hom_ref + self.het + self.hom_alt
+    }
+
+    /// Minor allele count, or minor carrier count when the matrix is carrier coded
+    fn minor_allele_count(&self, dosage: bool) -> usize {
+        match dosage {
+            true => {
+                let alt = self.het + 2 * self.hom_alt;
+                alt.min(2 * self.called() - alt)
+            },
+            false => self.het.min(self.hom_ref)
+        }
+    }
+
+    /// Minor allele frequency, or minor carrier frequency when the matrix is carrier coded
+    pub(crate) fn maf(&self, dosage: bool) -> f64 {
+        let copies = if dosage { 2 } else { 1 };
+        match self.called() {
+            0 => 0.,
+            n => self.minor_allele_count(dosage) as f64 / (copies * n) as f64
+        }
+    }
+
+    fn missing_rate(&self, n_samples: usize) -> f64 {
+        match n_samples {
+            0 => 0.,
+            n => self.missing as f64 / n as f64
+        }
+    }
+
+    fn is_monomorphic(&self) -> bool {
+        [self.hom_ref, self.het, self.hom_alt].iter().filter(|c| **c > 0).count() <= 1
+    }
+}
+
+impl FilterReport {
+
+    pub fn new() -> Self {
+        FilterReport { rows: Vec::new() }
+    }
+
+    /// print the filter report to stdout
+    pub fn print(&self) {
+        println!("#FILTER_REPORT");
+        println!("#filter\tvariants_removed\tsamples_removed");
+        for (name, n_var, n_samp) in &self.rows {
+            println!("{}\t{}\t{}", name, n_var, n_samp);
+        }
+    }
+}
+
+/// Restrict the matrix to the requested samples, variants and regions
+pub fn subset(gm: &mut matrix::GenoMatrix, variants: &[variants::Variant], opts: &SubsetOptions, report: &mut FilterReport) {
+    let mut sample_filter = |name: &str, pass: &dyn Fn(&str) -> bool| {
+        let before = gm.sample_indices().len();
+        let keep: Vec<usize> = gm.sample_indices().iter().filter(|s| pass(&gm.ids[**s])).copied().collect();
+        report.rows.push((String::from(name), 0, before - keep.len()));
+        gm.set_sample_indices(keep);
+    };
+    if let Some(ids) = &opts.keep_samples {
+        sample_filter("keep", &|id| ids.contains(id));
+    }
+    if let Some(ids) = &opts.remove_samples {
+        sample_filter("remove", &|id| !ids.contains(id));
+    }
+    let mut variant_filter = |name: &str, pass: &dyn Fn(&variants::Variant) -> bool| {
+        let before = gm.genotype_indices().len();
+        let keep: Vec<usize> = gm.genotype_indices().iter().filter(|g| pass(&variants[**g])).copied().collect();
+        report.rows.push((String::from(name), before - keep.len(), 0));
+        gm.set_genotype_indices(keep);
+    };
+    if let Some(ids) = &opts.extract {
+        variant_filter("extract", &|v| ids.contains(&v.id));
+    }
+    if let Some(ids) = &opts.exclude {
+        variant_filter("exclude", &|v| !ids.contains(&v.id));
+    }
+    if let Some(regions) = &opts.regions {
+        variant_filter("regions", &|v| regions.iter().any(|r| r.contains(v)));
+    }
+}
+
+/// Apply all requested filters to the matrix
+/// Subjects are filtered first so that variant statistics are computed on the retained subjects
+/// The matrix is dosage coded when any active call is 2, carrier coded otherwise
+pub fn apply(gm: &mut matrix::GenoMatrix, opts: &FilterOptions, report: &mut FilterReport) -> Result<(), String> {
+    if let Some(max_missing) = opts.max_sample_missing {
+        let variants = gm.genotype_indices().to_vec();
+        let before = gm.sample_indices().len();
+        let keep: Vec<usize> = gm.sample_indices().par_iter()
+            .filter(|s| {
+                let missing = variants.iter().filter(|g| *gm.genotype(**s, **g) >= matrix::MISSING_GENOTYPE).count();
+                variants.is_empty() || (missing as f64 / variants.len() as f64) <= max_missing
+            })
+            .copied()
+            .collect();
+        report.rows.push((format!("sample_missing>{:?}", max_missing), 0, before - keep.len()));
+        gm.set_sample_indices(keep);
+    }
+    let counts: Vec<(usize, VariantCounts)> = gm.genotype_indices().par_iter()
+        .map(|g| (*g, count_genotypes(gm, *g)))
+        .collect();
+    let n_samples = gm.sample_indices().len();
+    let dosage = counts.iter().any(|(_, c)| c.hom_alt > 0);
+    if opts.min_hwe_p.is_some() && !dosage {
+        return Err(String::from("the Hardy-Weinberg test needs 0/1/2 dosages, the genotypes are 0/1 carrier coded"))
+    }
+    let mut remaining: Vec<&(usize, VariantCounts)> = counts.iter().collect();
+    let mut variant_filter = |name: String, pass: &dyn Fn(&VariantCounts) -> bool| {
+        let before = remaining.len();
+        remaining.retain(|(_, c)| pass(c));
+        report.rows.push((name, before - remaining.len(), 0));
+    };
+    if let Some(max_missing) = opts.max_variant_missing {
+        variant_filter(format!("variant_missing>{:?}", max_missing), &|c| c.missing_rate(n_samples) <= max_missing);
+    }
+    if opts.remove_monomorphic {
+        variant_filter(String::from("monomorphic"), &|c| !c.is_monomorphic());
+    }
+    if let Some(min_maf) = opts.min_maf {
+        variant_filter(format!("maf<{:?}", min_maf), &|c| c.maf(dosage) >= min_maf);
+    }
+    if let Some(min_mac) = opts.min_mac {
+        variant_filter(format!("mac<{}", min_mac), &|c| c.minor_allele_count(dosage) >= min_mac);
+    }
+    if let Some(min_p) = opts.min_hwe_p {
+        variant_filter(format!("hwe_p<{:?}", min_p), &|c| statistics::hwe_exact(c.het, c.hom_ref, c.hom_alt) >= min_p);
+    }
+    let keep: Vec<usize> = remaining.iter().map(|(g, _)| *g).collect();
+    gm.set_genotype_indices(keep);
+    Ok(())
+}
+
+/// True when any active call is 2: the matrix is then dosage coded, otherwise carrier coded
+pub(crate) fn is_dosage_coded(gm: &matrix::GenoMatrix) -> bool {
+    let subjects = gm.sample_indices();
+    gm.genotype_indices().par_iter().any(|g| subjects.iter().any(|s| *gm.genotype(*s, *g) == 2))
+}
+
+/// Count the genotype calls of a variant over the active subjects
+/// Anything that is not 0, 1 or 2 is counted as missing, carriers of a 0/1 coded variant count as het
+pub(crate) fn count_genotypes(gm: &matrix::GenoMatrix, g: usize) -> VariantCounts {
+    let mut c = VariantCounts { hom_ref: 0, het: 0, hom_alt: 0, missing: 0 };
+    for s in gm.sample_indices() {
+        match *gm.genotype(*s, g) {
+            0 => c.hom_ref += 1,
+            1 => c.het += 1,
+            2 => c.hom_alt += 1,
+            _ => c.missing += 1
+        }
+    }
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    #[test]
+    fn counts_carrier_coding() {
+        let rows: Vec<(f64, Vec<u8>)> = [0, 1, 1, 0, 0, 1, 3, 0].iter().enumerate().map(|(i, g)| ((i % 2) as f64, vec![*g])).collect();
+        let gm = test_utils::matrix(&rows, matrix::Outcome::Binary);
+        let c = count_genotypes(&gm, 0);
+        assert_eq!(c, VariantCounts { hom_ref: 4, het: 3, hom_alt: 0, missing: 1 });
+        assert_eq!(c.minor_allele_count(false), 3);
+        assert!((c.maf(false) - 3. / 7.).abs() < 1e-12);
+        assert!((c.missing_rate(8) - 0.125).abs() < 1e-12);
+        assert!(!c.is_monomorphic());
+    }
+
+    #[test]
+    fn counts_dosage_coding() {
+        let c = VariantCounts { hom_ref: 6, het: 3, hom_alt: 1, missing: 0 };
+        assert_eq!(c.minor_allele_count(true), 5);
+        assert!((c.maf(true) - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn hwe_rejected_for_carrier_coding() {
+        let rows: Vec<(f64, Vec<u8>)> = (0..10).map(|i| ((i % 2) as f64, vec![(i % 3 == 0) as u8])).collect();
+        let mut gm = test_utils::matrix(&rows, matrix::Outcome::Binary);
+        let mut opts = FilterOptions {
+            min_maf: Some(0.1),
+            min_mac: None,
+            max_variant_missing: None,
+            min_hwe_p: None,
+            remove_monomorphic: true,
+            max_sample_missing: None
+        };
+        let mut report = FilterReport::new();
+        assert!(apply(&mut gm, &opts, &mut report).is_ok());
+        assert_eq!(gm.genotype_indices(), &[0]);
+        opts.min_hwe_p = Some(1e-6);
+        assert!(apply(&mut gm, &opts, &mut report).is_err());
+    }
+}
@@ -4,10 +4,13 @@
 //! Random Forest Algorithm
 //! Manages the creation and organization of decision trees
 
+use crate::criterion;
 use crate::tree;
 use crate::matrix;
+use crate::proximity;
 use crate::variants;
 use crate::statistics;
+use crate::utils;
 
 use rayon::prelude::*;
 use indicatif::ParallelProgressIterator;
@@ -18,81 +21,161 @@ use std::collections::HashMap;
 /// Hyperparameters
 /// n_tree = number of trees
 /// mtry = fraction of variants to be selected for each tree
-/// min_node_size = minimum number of samples in a node to be considered for a split
+/// max_depth = maximum depth of a tree
 /// subj_fraction = fraction of subjects to be selected for each tree
+/// outcome = type of outcome (binary, continuous, categorical or survival)
+/// bootstrap = sample subjects with replacement
+/// mtry_mode = whether mtry is drawn once per tree or at every split
+/// criterion = split criterion used to score candidate variants
+/// selection = impurity or conditional-inference choice of the split variant (see tree::SplitSelection)
+/// stopping = min node size, min leaf size, min impurity decrease and max leaves (see tree::StoppingRules)
+#[derive(Clone, Copy)]
 pub struct HyperParameters {
     pub n_tree: i32,
     pub mtry: f64,
     pub max_depth: i32,
     pub subj_fraction: f64,
-    pub continuous_outcome: bool
+    pub outcome: matrix::Outcome,
+    pub bootstrap: bool,
+    pub mtry_mode: MtryMode,
+    pub criterion: criterion::Criterion,
+    pub selection: tree::SplitSelection,
+    pub stopping: tree::StoppingRules
 }
 
+/// Where the mtry fraction of variants is drawn
+/// PerTree: once in make_slice, every node of the tree sees the same variants
+/// PerNode: each split draws a fresh subset of all active variants, only its columns are read
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MtryMode {
+    PerTree,
+    PerNode
+}
+
+/// Importance a variant got from the individual trees that split on it
+/// sum and sum_sq run over those trees, so the mean over all trees divides by the number grown
+#[derive(Clone, Copy, Default)]
+pub struct TreeImportance {
+    pub n_trees: usize,
+    pub sum: f64,
+    pub sum_sq: f64
+}
+
+/// The forest keeps its trees only when asked to (keep_trees), e.g. for printing or serialization.
+/// Otherwise each tree is reduced into the importance accumulator as it is grown and then dropped.
+/// With track_oob every tree also adds its predictions for the subjects it did not see.
 pub struct Forest {
     hyperparameters: HyperParameters,
-    pub trees: Option<Vec<tree::Node>>
+    keep_trees: bool,
+    track_oob: bool,
+    importances: Option<HashMap<usize, f64>>,
+    tree_importances: Option<HashMap<usize, TreeImportance>>,
+    oob: Option<OobPredictions>,
+    pub trees: Option<Vec<tree::Node>>,
+    bags: Option<Vec<Vec<usize>>> // subjects drawn for each kept tree, in the order of trees
+}
+
+/// Per-thread results of growing trees, reduced into the forest once growth is done
+struct Accumulator {
+    importances: HashMap<usize, f64>,
+    tree_importances: HashMap<usize, TreeImportance>,
+    oob: Option<OobPredictions>, // only when tracking OOB predictions
+    trees: Vec<tree::Node>,
+    bags: Vec<Vec<usize>>
+}
+
+/// Summed out-of-bag predictions and number of trees contributing, per subject of the matrix
+struct OobPredictions {
+    sums: Vec<Vec<f64>>,
+    counts: Vec<usize>
 }
 
 impl Forest {
 
-    pub fn new(hp: HyperParameters) -> Self {
+    pub fn new(hp: HyperParameters, keep_trees: bool) -> Self {
 
         Forest {
             hyperparameters: hp,
-            trees: None
+            keep_trees,
+            track_oob: false,
+            importances: None,
+            tree_importances: None,
+            oob: None,
+            trees: None,
+            bags: None
         }
     }
 
-    pub fn update_hyperparameters(&mut self, hp: HyperParameters) {
-        self.hyperparameters = hp
+    /// Collect out-of-bag predictions while growing (needed for oob_error and oob_predictions)
+    pub fn set_track_oob(&mut self, track_oob: bool) {
+        self.track_oob = track_oob
+    }
+
+    /// Free the kept trees and their bags once the forest is no longer needed
+    pub fn drop_trees(&mut self) {
+        self.trees = None;
+        self.bags = None
     }
 
     pub fn grow(&mut self, gm: &matrix::GenoMatrix) -> Result<(), io::Error> {
-        let t: Vec<tree::Node> = (0..self.hyperparameters.n_tree).into_par_iter()
-        .progress_count(self.hyperparameters.n_tree as u64)
-        .map(|_| -> tree::Node {
-            match make_tree(gm, &self.hyperparameters) {
-                Ok(tree) => return tree,
-                Err(_) => return tree::Node::empty_node()
-            };
-        }).collect();
-        self.trees = Some(t);
+        let hp = &self.hyperparameters;
+        let (keep_trees, track_oob) = (self.keep_trees, self.track_oob);
+        let n_subjects = gm.ids.len();
+        // Each tree is folded into a per-thread accumulator and only retained if keep_trees
+        let acc = (0..hp.n_tree).into_par_iter()
+        .progress_count(hp.n_tree as u64)
+        .map(|_| -> (tree::Node, Vec<usize>) {
+            match make_tree(gm, hp) {
+                Ok(grown) => grown,
+                Err(_) => (tree::Node::empty_node(), Vec::new())
+            }
+        })
+        .fold(|| Accumulator::new(n_subjects, track_oob), |mut acc, (tree, bag)| {
+            add_tree_importance(&mut acc.importances, &mut acc.tree_importances, &tree);
+            if let Some(oob) = &mut acc.oob {
+                oob.add_tree(gm, &tree, &bag);
+            }
+            if keep_trees {
+                acc.trees.push(tree);
+                acc.bags.push(bag);
+            }
+            acc
+        })
+        .reduce(|| Accumulator::new(n_subjects, track_oob), Accumulator::merge);
+        self.importances = Some(acc.importances);
+        self.tree_importances = Some(acc.tree_importances);
+        self.oob = acc.oob;
+        self.trees = if keep_trees { Some(acc.trees) } else { None };
+        self.bags = if keep_trees { Some(acc.bags) } else { None };
         Ok(())
     }
 
-    fn get_var_importances(&self) -> HashMap<usize, f64> {
-        let mut tree_imps: HashMap<usize, Vec<f64>> = HashMap::new();
-        let mut var_imps: HashMap<usize, f64> = HashMap::new();
-        for tree in self.trees.as_ref().unwrap() {
-            if !tree.is_empty {
-                for (var, imp) in tree.get_importance() {
-                    if tree_imps.contains_key(&var) {
-                        let n_imp = &mut imp.to_vec();
-                        let mut n_vec = tree_imps[&var].to_vec();
-                        n_vec.append(n_imp);
-                        tree_imps.remove(&var);
-                        tree_imps.insert(var, n_vec);
-                    }
-                    else {
-                        tree_imps.insert(var, imp);
-                    }
-                }
-            }
-        };
-        for (var, imp) in tree_imps {
-            //var_imps.insert(var, statistics::mean(&imp.iter().map(|x| x).collect()));
-            var_imps.insert(var, imp.iter().sum());
-        };
-        var_imps
+    pub fn get_var_importances(&self) -> HashMap<usize, f64> {
+        match &self.importances {
+            Some(imps) => imps.clone(),
+            None => HashMap::new()
+        }
     }
 
+    /// Per-tree importance of every variant split on in the last grown forest
+    pub fn get_tree_importances(&self) -> HashMap<usize, TreeImportance> {
+        match &self.tree_importances {
+            Some(imps) => imps.clone(),
+            None => HashMap::new()
+        }
+    }
+
+    /// Number of trees grown in the last forest
+    pub fn n_trees(&self) -> usize {
+        self.hyperparameters.n_tree.max(0) as usize
+    }
 
     pub fn keep_vars(&self, z_keep: f64) -> Vec<usize> {
         let mut vars: Vec<usize> = Vec::new();
         let tree_imps = self.get_var_importances();
         let importances: Vec<&f64> = tree_imps.values().collect();
-        let imp_mean: f64 = statistics::mean(&importances);
-        let imp_sd: f64 = statistics::std_deviation(&importances);
+        let imp_mean: f64 = statistics::nonnegative_mean(&importances);
+        let imp_sd: f64 = statistics::nonnegative_std_deviation(&importances);
         println!("{:?}", imp_mean);
         for (var, imp) in tree_imps {
             if ((imp - imp_mean) / imp_sd) >= z_keep {
@@ -102,27 +185,246 @@ impl Forest {
         vars
     }
     
+    /// Predict the outcome of each given subject, averaged over the retained trees
+    /// Class probabilities for binary and categorical outcomes, a single value if continuous
+    /// Requires the trees to be kept (see Forest::new)
+    pub fn predict(&self, gm: &matrix::GenoMatrix, subjects: &[usize]) -> Vec<Vec<f64>> {
+        let trees = match &self.trees {
+            Some(t) => t,
+            None => return vec![Vec::new(); subjects.len()]
+        };
+        subjects.par_iter().map(|s| {
+            let mut total: Vec<f64> = Vec::new();
+            let mut n_pred: f64 = 0.;
+            for tree in trees {
+                if let Some(value) = tree.predict(gm, *s) {
+                    add_values(&mut total, value);
+                    n_pred += 1.;
+                }
+            }
+            total.iter().map(|t| t / n_pred).collect()
+        }).collect()
+    }
+
+    /// Out-of-bag prediction of every subject of the matrix, averaged over the trees that did not see it
+    /// Empty for subjects that were in the bag of every tree, or when OOB tracking is off
+    pub fn oob_predictions(&self) -> Vec<Vec<f64>> {
+        match &self.oob {
+            Some(oob) => oob.sums.iter().zip(oob.counts.iter())
+                .map(|(sum, n)| sum.iter().map(|v| v / *n as f64).collect())
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Out-of-bag error over the active subjects that have an OOB prediction
+    /// Misclassification rate for binary and categorical outcomes, mean squared error if continuous
+    /// and 1 - C-index (with the summed cumulative hazard as risk) for survival
+    pub fn oob_error(&self, gm: &matrix::GenoMatrix) -> Option<f64> {
+        let predictions = self.oob_predictions();
+        if predictions.is_empty() {
+            return None
+        }
+        let subjects: Vec<usize> = gm.sample_indices().iter().filter(|s| !predictions[**s].is_empty()).copied().collect();
+        if subjects.is_empty() {
+            return None
+        }
+        let observed: Vec<f64> = subjects.iter().map(|s| gm.response()[*s]).collect();
+        let error = match gm.outcome {
+            matrix::Outcome::Continuous => {
+                let predicted: Vec<f64> = subjects.iter().map(|s| predictions[*s][0]).collect();
+                statistics::mean_squared_error(&observed, &predicted)
+            },
+            matrix::Outcome::Survival => {
+                let risk: Vec<f64> = subjects.iter().map(|s| predictions[*s].iter().sum()).collect();
+                let events: Vec<f64> = subjects.iter().map(|s| gm.events[*s]).collect();
+                1. - statistics::concordance_index(&observed, &events, &risk)
+            },
+            _ => {
+                let predicted: Vec<f64> = subjects.iter().map(|s| utils::argmax(&predictions[*s]) as f64).collect();
+                statistics::misclassification_rate(&observed, &predicted)
+            }
+        };
+        Some(error)
+    }
+
+    /// Proximity of the active subjects, see proximity::compute
+    /// Requires the trees to be kept (see Forest::new)
+    pub fn proximity(&self, gm: &matrix::GenoMatrix, oob_only: bool) -> Option<proximity::Proximity> {
+        match (&self.trees, &self.bags) {
+            (Some(trees), Some(bags)) => Some(proximity::compute(gm, trees, bags, oob_only)),
+            _ => None
+        }
+    }
+
     /// print the variant + importance to stdout
-    pub fn print_var_importance(&self, variants: &Vec<variants::Variant>) {
+    pub fn print_var_importance(&self, variants: &[variants::Variant]) {
         let tree_imps = self.get_var_importances();
+        println!("#id\timportance\t{}", variants::DESCRIBE_HEADER);
         for (var, imp) in tree_imps {
-            println!("{}\t{:?}", variants[var].id, imp);
+            println!("{}\t{:?}\t{}", variants[var].id, imp, variants[var].describe());
         }
     }
 }
 
 
 
+impl Accumulator {
+
+    fn new(n_subjects: usize, track_oob: bool) -> Self {
+        Accumulator {
+            importances: HashMap::new(),
+            tree_importances: HashMap::new(),
+            oob: if track_oob { Some(OobPredictions::new(n_subjects)) } else { None },
+            trees: Vec::new(),
+            bags: Vec::new()
+        }
+    }
+
+    /// Combine two accumulators (used to reduce the per-thread results)
+    fn merge(mut self, other: Accumulator) -> Self {
+        self.importances = merge_importances(self.importances, other.importances);
+        for (var, t) in other.tree_importances {
+            let entry = self.tree_importances.entry(var).or_default();
+            entry.n_trees += t.n_trees;
+            entry.sum += t.sum;
+            entry.sum_sq += t.sum_sq;
+        }
+        if let (Some(oob), Some(other_oob)) = (&mut self.oob, other.oob) {
+            oob.merge(other_oob);
+        }
+        self.trees.extend(other.trees);
+        self.bags.extend(other.bags);
+        self
+    }
+}
+
+impl OobPredictions {
+
+    fn new(n_subjects: usize) -> Self {
+        OobPredictions {
+            sums: vec![Vec::new(); n_subjects],
+            counts: vec![0; n_subjects]
+        }
+    }
+
+    /// Add the predictions of a tree for the active subjects that are not in its bag
+    fn add_tree(&mut self, gm: &matrix::GenoMatrix, tree: &tree::Node, bag: &[usize]) {
+        let mut in_bag: Vec<bool> = vec![false; self.counts.len()];
+        for s in bag {
+            in_bag[*s] = true;
+        }
+        for s in gm.sample_indices() {
+            if in_bag[*s] {
+                continue
+            }
+            if let Some(value) = tree.predict(gm, *s) {
+                add_values(&mut self.sums[*s], value);
+                self.counts[*s] += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: OobPredictions) {
+        for (s, sum) in other.sums.iter().enumerate() {
+            add_values(&mut self.sums[s], sum);
+            self.counts[s] += other.counts[s];
+        }
+    }
+}
+
+/// Element-wise add values into a running sum, the sum starts out empty
+fn add_values(sum: &mut Vec<f64>, values: &[f64]) {
+    if values.is_empty() {
+        return
+    }
+    if sum.is_empty() {
+        *sum = vec![0.; values.len()];
+    }
+    sum.iter_mut().zip(values.iter()).for_each(|(t, v)| *t += v);
+}
+
+/// Add the summed importances of a single tree to the accumulators
+fn add_tree_importance(imps: &mut HashMap<usize, f64>, tree_imps: &mut HashMap<usize, TreeImportance>, tree: &tree::Node) {
+    if tree.is_empty {
+        return
+    }
+    for (var, imp) in tree.get_importance() {
+        let total = imp.iter().sum::<f64>();
+        *imps.entry(var).or_insert(0.) += total;
+        let t = tree_imps.entry(var).or_default();
+        t.n_trees += 1;
+        t.sum += total;
+        t.sum_sq += total * total;
+    }
+}
+
+/// Combine two importance accumulators (used to reduce the per-thread results)
+fn merge_importances(mut a: HashMap<usize, f64>, b: HashMap<usize, f64>) -> HashMap<usize, f64> {
+    for (var, imp) in b {
+        *a.entry(var).or_insert(0.) += imp;
+    }
+    a
+}
+
 /// Connection to the tree lib for making the decision trees
 /// Outside of impl block since it is 'kind of' an independent operator
 /// that spawns / returns the tree
-fn make_tree(gm: &matrix::GenoMatrix, hp: &HyperParameters) -> Result<tree::Node, io::Error> {
-    let sample = gm.make_slice(hp.mtry, hp.subj_fraction);
-    let data = gm.get_slice_data(&sample);
-    let tree_data = tree::NodeData {
-        phenos: data.0,
-        phenos_shuffle: data.1,
-        genos: data.2
+fn make_tree(gm: &matrix::GenoMatrix, hp: &HyperParameters) -> Result<(tree::Node, Vec<usize>), io::Error> {
+    let (tree_mtry, node_mtry) = match hp.mtry_mode {
+        MtryMode::PerTree => (hp.mtry, None),
+        MtryMode::PerNode => (1., Some(hp.mtry))
+    };
+    let sample = gm.make_slice(tree_mtry, hp.subj_fraction, hp.bootstrap);
+    // per-node trees draw from all active variants and extra trees score a few of the tree's variants
+    // at each split, their candidate columns are looked up per split
+    let extra_trees = matches!(hp.selection, tree::SplitSelection::ExtraTrees { .. });
+    let genos = gm.slice_genotypes(&sample, node_mtry.is_none() && !extra_trees);
+    let tree_data = gm.get_slice_data(&sample, &genos);
+    let params = tree::TreeParameters {
+        max_depth: hp.max_depth,
+        outcome: hp.outcome,
+        n_classes: gm.n_classes,
+        time_grid: gm.time_grid.to_vec(),
+        node_mtry,
+        n_forced: gm.covariate_indices().len(),
+        stopping: hp.stopping,
+        criterion: hp.criterion.make(gm.n_classes),
+        selection: hp.selection
     };
-    Ok(tree::Node::grow(tree_data, hp.max_depth, sample, hp.continuous_outcome))
+    let tree = tree::Node::grow(tree_data, &sample, &params);
+    Ok((tree, sample.subj_ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    #[test]
+    fn grows_on_dosage_genotypes() {
+        // variant 0 carries the outcome (any alt allele), variant 1 is noise, both use 0/1/2 dosages
+        let rows: Vec<(f64, Vec<u8>)> = (0..300).map(|i| {
+            let g0 = (i % 3) as u8;
+            ((g0 > 0) as u8 as f64, vec![g0, ((i / 3) % 3) as u8])
+        }).collect();
+        let gm = test_utils::matrix(&rows, matrix::Outcome::Binary);
+        for selection in [tree::SplitSelection::Impurity, tree::SplitSelection::ConditionalInference { alpha: 0.05, permutations: 0 }] {
+            let hp = HyperParameters {
+                n_tree: 10,
+                mtry: 1.,
+                max_depth: 2,
+                subj_fraction: 0.8,
+                outcome: matrix::Outcome::Binary,
+                bootstrap: false,
+                mtry_mode: MtryMode::PerTree,
+                criterion: criterion::Criterion::Gini,
+                selection,
+                stopping: tree::StoppingRules { min_node_size: None, min_leaf_size: None, min_impurity_decrease: None, max_leaves: None }
+            };
+            let mut f = Forest::new(hp, true);
+            f.grow(&gm).unwrap();
+            assert!(f.trees.as_ref().unwrap().iter().all(|t| t.var == 0 && t.threshold == 0));
+        }
+    }
 }
@@ -0,0 +1,156 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Linkage disequilibrium between variants
+//! Pruning of the active variant set before growth and clumping of the final importances
+
+use crate::filters;
+use crate::matrix;
+use crate::variants;
+
+use rayon::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// An independent locus from clumping, the lead variant and its proxies in LD
+pub struct Clump {
+    pub lead: usize,
+    pub importance: f64,
+    pub proxies: Vec<usize>
+}
+
+/// Squared correlation of the genotypes of two variants
+/// Only subjects with a call at both variants are used
+pub fn r_squared(gm: &matrix::GenoMatrix, a: usize, b: usize) -> f64 {
+    let mut n: f64 = 0.;
+    let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0., 0., 0., 0., 0.);
+    for s in gm.sample_indices() {
+        let ga = *gm.genotype(*s, a);
+        let gb = *gm.genotype(*s, b);
+        if ga >= matrix::MISSING_GENOTYPE || gb >= matrix::MISSING_GENOTYPE {
+            continue
+        }
+        let (x, y) = (ga as f64, gb as f64);
+        n += 1.;
+        sa += x;
+        sb += y;
+        saa += x * x;
+        sbb += y * y;
+        sab += x * y;
+    }
+    let cov = sab - sa * sb / n;
+    let var_a = saa - sa * sa / n;
+    let var_b = sbb - sb * sb / n;
+    if n < 2. || var_a <= 0. || var_b <= 0. {
+        return 0.
+    }
+    (cov * cov) / (var_a * var_b)
+}
+
+/// Sort the given variants into chromosomes, ordered by position
+/// Variants without a known position are left out
+fn by_chromosome(variants: &[variants::Variant], indices: &[usize]) -> Vec<Vec<usize>> {
+    let mut chroms: HashMap<&str, Vec<usize>> = HashMap::new();
+    for g in indices {
+        if let (Some(c), Some(_)) = (&variants[*g].chrom, variants[*g].pos) {
+            chroms.entry(c.as_str()).or_default().push(*g);
+        }
+    }
+    chroms.into_values().map(|mut v| {
+        v.sort_by_key(|g| variants[*g].pos);
+        v
+    }).collect()
+}
+
+/// Prune the active variants so that no pair within window_bp has r2 above max_r2
+/// Of each correlated pair the variant with the lower minor allele frequency (minor carrier frequency
+/// for carrier coding, see filters) is dropped
+/// Returns the number of variants removed
+pub fn prune(gm: &mut matrix::GenoMatrix, variants: &[variants::Variant], window_bp: u64, max_r2: f64) -> usize {
+    let active = gm.genotype_indices().to_vec();
+    let chroms = by_chromosome(variants, &active);
+    let matrix: &matrix::GenoMatrix = gm;
+    let dosage = filters::is_dosage_coded(matrix);
+    let removed: Vec<usize> = chroms.par_iter().flat_map(|chrom| {
+        let freqs: Vec<f64> = chrom.iter().map(|g| filters::count_genotypes(matrix, *g).maf(dosage)).collect();
+        let mut kept: Vec<bool> = vec![true; chrom.len()];
+        for i in 0..chrom.len() {
+            let mut j = i + 1;
+            while kept[i] && j < chrom.len() && variants[chrom[i]].is_near(&variants[chrom[j]], window_bp) {
+                if kept[j] && r_squared(matrix, chrom[i], chrom[j]) > max_r2 {
+                    if freqs[j] < freqs[i] {
+                        kept[j] = false;
+                    } else {
+                        kept[i] = false;
+                    }
+                }
+                j += 1;
+            }
+        }
+        chrom.iter().zip(kept.iter()).filter(|(_, k)| !**k).map(|(g, _)| *g).collect::<Vec<usize>>()
+    }).collect();
+    let n_removed = removed.len();
+    let removed: HashSet<usize> = removed.into_iter().collect();
+    gm.set_genotype_indices(active.into_iter().filter(|g| !removed.contains(g)).collect());
+    n_removed
+}
+
+/// Clump variants by importance into independent loci
+/// The most important unassigned variant becomes a lead, and every unassigned variant within window_bp
+/// with r2 of at least min_r2 to the lead is reported as its proxy
+/// Only variants with a positive importance can lead a clump
+pub fn clump(gm: &matrix::GenoMatrix, variants: &[variants::Variant], importances: &HashMap<usize, f64>, window_bp: u64, min_r2: f64) -> Vec<Clump> {
+    let mut ranked: Vec<(usize, f64)> = importances.iter().map(|(g, imp)| (*g, *imp)).collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut assigned: Vec<bool> = vec![false; ranked.len()];
+    let mut clumps: Vec<Clump> = Vec::new();
+    for i in 0..ranked.len() {
+        let (lead, importance) = ranked[i];
+        if assigned[i] || importance <= 0. {
+            continue
+        }
+        assigned[i] = true;
+        let candidates: Vec<usize> = (i + 1..ranked.len())
+            .filter(|j| !assigned[*j] && variants[lead].is_near(&variants[ranked[*j].0], window_bp))
+            .collect();
+        let proxies: Vec<usize> = candidates.par_iter()
+            .filter(|j| r_squared(gm, lead, ranked[**j].0) >= min_r2)
+            .copied()
+            .collect();
+        for j in &proxies {
+            assigned[*j] = true;
+        }
+        clumps.push(Clump {
+            lead,
+            importance,
+            proxies: proxies.iter().map(|j| ranked[*j].0).collect()
+        });
+    }
+    clumps
+}
+
+/// print the clumps to stdout
+pub fn print_clumps(clumps: &[Clump], variants: &[variants::Variant]) {
+    println!("#CLUMPS");
+    println!("#lead\timportance\t{}\tn_proxies\tproxies", variants::DESCRIBE_HEADER);
+    for c in clumps {
+        let proxies: Vec<&str> = c.proxies.iter().map(|g| variants[*g].id.as_str()).collect();
+        println!("{}\t{:?}\t{}\t{}\t{}", variants[c.lead].id, c.importance, variants[c.lead].describe(), proxies.len(), proxies.join(","));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    #[test]
+    fn prune_keeps_the_higher_carrier_frequency() {
+        // carrier frequencies 0.7 and 0.4 (minor 0.3 and 0.4), r2 = 0.29
+        let rows: Vec<(f64, Vec<u8>)> = (0..10).map(|i| (0., vec![(i < 7) as u8, (i < 4) as u8])).collect();
+        let mut gm = test_utils::matrix(&rows, matrix::Outcome::Binary);
+        let variants = vec![variants::Variant::from_fields(&["a", "1", "100"]), variants::Variant::from_fields(&["b", "1", "200"])];
+        assert_eq!(prune(&mut gm, &variants, 1000, 0.2), 1);
+        assert_eq!(gm.genotype_indices(), &[1]);
+    }
+}
@@ -8,9 +8,22 @@ pub mod forest;
 pub mod utils;
 pub mod variants;
 pub mod statistics;
+pub mod filters;
+pub mod ld;
+pub mod criterion;
+pub mod export;
+pub mod proximity;
+pub mod cv;
+pub mod tune;
+pub mod stability;
+pub mod aggregate;
+pub mod prune;
+pub mod structure;
 
 use clap::Parser;
 
+use std::collections::HashSet;
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "Find genetic interactions from GWAS-scale data", long_about = None)]
 struct Args {
@@ -18,67 +31,419 @@ struct Args {
     verbose: bool,
     #[clap(short, long, help="Path to input file")]
     file_path: String,
-    #[clap(long, help="Path to file with variant names.")]
-    variant_file_path: String,
-    #[clap(long, help="Number of trees in selection forest.")]
-    n_tree: i32,
-    #[clap(long, help="MTRY fraction for selection forest.")]
-    mtry: f64,
-    #[clap(long, help="Max depth for selection forest.")]
-    max_depth: i32,
-    #[clap(long, help="Subject fraction for selection forest.")]
-    subj_fraction: f64,
-    #[clap(long, help="Number of trees for iterative forest.")]
-    n_tree_2: i32,
-    #[clap(long, help="MTRY fraction for iterative forest.")]
-    mtry_2: f64,
-    #[clap(long, help="Max depth for iterative forest.")]
-    max_depth_2: i32,
-    #[clap(long, help="Subject fraction for iterative forest.")]
-    subj_fraction_2: f64,
-    #[clap(long, help="Number of iterations for iterative forest.")]
-    n_iter: usize,
-    #[clap(long, help="Z score to keep variants after selection forest.")]
-    z_keep: f64,
+    #[clap(long, help="Path to file with variant names, one row per genotype column (a .gfc cache carries its own).")]
+    variant_file_path: Option<String>,
+    #[clap(long, help="The first row of the input file is a header with variant IDs.")]
+    header: bool,
+    #[clap(long, required_unless_present="convert-only", help="Number of trees in selection forest.")]
+    n_tree: Option<i32>,
+    #[clap(long, required_unless_present="convert-only", help="MTRY fraction for selection forest.")]
+    mtry: Option<f64>,
+    #[clap(long, required_unless_present="convert-only", help="Max depth for selection forest.")]
+    max_depth: Option<i32>,
+    #[clap(long, required_unless_present="convert-only", help="Subject fraction for selection forest.")]
+    subj_fraction: Option<f64>,
+    #[clap(long, required_unless_present="convert-only", help="Number of trees for iterative forest.")]
+    n_tree_2: Option<i32>,
+    #[clap(long, required_unless_present="convert-only", help="MTRY fraction for iterative forest.")]
+    mtry_2: Option<f64>,
+    #[clap(long, required_unless_present="convert-only", help="Max depth for iterative forest.")]
+    max_depth_2: Option<i32>,
+    #[clap(long, required_unless_present="convert-only", help="Subject fraction for iterative forest.")]
+    subj_fraction_2: Option<f64>,
+    #[clap(long, required_unless_present="convert-only", help="Number of iterations for iterative forest.")]
+    n_iter: Option<usize>,
+    #[clap(long, required_unless_present="convert-only", help="Z score to keep variants after selection forest.")]
+    z_keep: Option<f64>,
     #[clap(long, help="Outcome is a continuous variable.")]
     continuous_outcome: bool,
+    #[clap(long, conflicts_with="continuous-outcome", help="Outcome is categorical, coded as class labels 0..k-1.")]
+    categorical_outcome: bool,
+    #[clap(long, conflicts_with_all=&["continuous-outcome", "categorical-outcome"], help="Outcome is time-to-event: column 2 is the time, column 3 the event (1) or censoring (0).")]
+    survival_outcome: bool,
+    #[clap(long, default_value="phenotype", possible_values=&["phenotype", "none", "ratio", "downsample", "weights", "quantile"],
+        help="Balancing of subject sampling: phenotype-weighted (default), none, case:control ratio, down-sample to the minority class, sample weights or outcome quantile strata.")]
+    balance: String,
+    #[clap(long, help="Target case:control ratio for --balance ratio.")]
+    case_ratio: Option<f64>,
+    #[clap(long, help="File of sample IDs and weights for --balance weights.")]
+    sample_weights: Option<String>,
+    #[clap(long, default_value="4", help="Number of outcome quantile strata for --balance quantile.")]
+    quantile_strata: usize,
+    #[clap(long, possible_values=&["gini", "entropy", "chi-square", "g-test", "sdr", "mse", "mad", "log-rank"],
+        help="Split criterion: gini (default), entropy, chi-square or g-test for binary/categorical, sdr (default), mse or mad for continuous, log-rank for survival.")]
+    split_criterion: Option<String>,
+    #[clap(long, default_value="impurity", possible_values=&["impurity", "conditional", "extra-trees"],
+        help="Choose the split variant by impurity, by association test p-value (conditional inference), or among a few random variants and thresholds (extra-trees).")]
+    split_selection: String,
+    #[clap(long, default_value="0.05", help="Bonferroni-adjusted p-value a node must reach to split with --split-selection conditional.")]
+    ci_alpha: f64,
+    #[clap(long, default_value="0", help="Permutations for the conditional-inference p-values (0 uses the asymptotic test).")]
+    ci_permutations: usize,
+    #[clap(long, default_value="10", help="Random candidate variants scored at each node with --split-selection extra-trees.")]
+    extra_candidates: usize,
+    #[clap(long, help="Minimum number of subjects in a node to attempt a split.")]
+    min_node_size: Option<usize>,
+    #[clap(long, help="Minimum number of subjects on each side of a split.")]
+    min_leaf_size: Option<usize>,
+    #[clap(long, help="Minimum decrease of the split score (Gini decrease, sd reduction or log-rank statistic) to split.")]
+    min_impurity_decrease: Option<f64>,
+    #[clap(long, help="Maximum number of leaves per tree.")]
+    max_leaves: Option<usize>,
+    #[clap(long, help="Minimum node size to split in the iterative forest.")]
+    min_node_size_2: Option<usize>,
+    #[clap(long, help="Minimum leaf size in the iterative forest.")]
+    min_leaf_size_2: Option<usize>,
+    #[clap(long, help="Minimum impurity decrease to split in the iterative forest.")]
+    min_impurity_decrease_2: Option<f64>,
+    #[clap(long, help="Maximum number of leaves per tree in the iterative forest.")]
+    max_leaves_2: Option<usize>,
+    #[clap(long, help="Sample subjects with replacement (bootstrap) instead of without.")]
+    bootstrap: bool,
+    #[clap(long, default_value="per-tree", possible_values=&["per-tree", "per-node"],
+        help="Draw the mtry fraction of variants once per tree, or at every split from all active variants.")]
+    mtry_mode: String,
+    #[clap(long, help="Report the out-of-bag error of every forest.")]
+    oob_error: bool,
+    #[clap(long, default_value="20", help="Number of top variants compared between iterations for the importance stability.")]
+    stability_top: usize,
+    #[clap(long, help="Stop iterating once the Spearman correlation of importances with the previous forest reaches this value.")]
+    converge_spearman: Option<f64>,
+    #[clap(long, help="Stop iterating once the top variant Jaccard overlap with the previous forest reaches this value.")]
+    converge_jaccard: Option<f64>,
+    #[clap(long, default_value="2", help="Iterations in a row that must meet the convergence thresholds before stopping.")]
+    converge_rounds: usize,
+    #[clap(long, default_value="1000", help="Bootstrap resamples for the confidence intervals of the aggregated importances.")]
+    importance_bootstrap: usize,
+    #[clap(long, default_value="0.95", help="Confidence level of the aggregated importance intervals.")]
+    importance_ci: f64,
+    #[clap(long, help="After each iteration drop this fraction of the active variants with the lowest importance.")]
+    prune_fraction: Option<f64>,
+    #[clap(long, conflicts_with="prune-fraction", help="After each iteration drop the active variants with an importance z score below this value.")]
+    prune_z: Option<f64>,
+    #[clap(long, help="Stop iterating once this many variants (or fewer) remain, never pruning below it.")]
+    target_variants: Option<usize>,
+    #[clap(long, help="Stop iterating once the OOB error is higher than in the previous iteration.")]
+    stop_on_oob_increase: bool,
+    #[clap(long, help="Write per-sample predictions from the final forest to this file.")]
+    predictions_out: Option<String>,
     #[clap(long, help="Write forest to stdout (very verbose output)")]
     output_forest: bool,
-    #[clap(short, long, help="Number of threads to use.")]
-    threads: usize
+    #[clap(long, help="Write the trees of the final forest as nested JSON to this file.")]
+    trees_json: Option<String>,
+    #[clap(long, help="Write the trees of the final forest as Graphviz DOT to this file.")]
+    trees_dot: Option<String>,
+    #[clap(long, help="Write the sample proximities of the final forest (pairs sharing a terminal node) to this file.")]
+    proximity_out: Option<String>,
+    #[clap(long, default_value="all", possible_values=&["all", "oob"],
+        help="Compute proximities over all subjects, or only over trees where both subjects are out of bag.")]
+    proximity_mode: String,
+    #[clap(long, help="Write MDS coordinates of the samples from the proximities to this file.")]
+    mds_out: Option<String>,
+    #[clap(long, default_value="2", help="Number of MDS dimensions.")]
+    mds_dims: usize,
+    #[clap(long, help="Write hierarchical (average linkage) sample clusters from the proximities to this file.")]
+    clusters_out: Option<String>,
+    #[clap(long, default_value="2", help="Number of sample clusters.")]
+    n_clusters: usize,
+    #[clap(long, help="Run k-fold cross-validation of the full run with this many folds and exit.")]
+    cv_folds: Option<usize>,
+    #[clap(long, help="Stratify the cross-validation folds by outcome class, event status or outcome rank.")]
+    cv_stratified: bool,
+    #[clap(long, multiple_occurrences(true), help="Search a setting (e.g. mtry=0.1,0.2,0.5 or, for random search, mtry=0.05:0.5), may be repeated. Tunes the run and exits.")]
+    tune_param: Vec<String>,
+    #[clap(long, default_value="grid", possible_values=&["grid", "random"], help="Try every combination of the tuned values, or random draws.")]
+    tune_search: String,
+    #[clap(long, default_value="20", help="Number of configurations drawn with --tune-search random.")]
+    tune_samples: usize,
+    #[clap(long, default_value="oob-error", possible_values=&["oob-error", "auc", "accuracy", "r2", "mse", "c-index"],
+        help="Metric ranking the configurations: OOB error of the final forest, or a cross-validation mean over --cv-folds folds (default 5).")]
+    tune_metric: String,
+    #[clap(long, help="Write the options of the best configuration to this file.")]
+    tune_out: Option<String>,
+    #[clap(short, long, required_unless_present="convert-only", help="Number of threads to use.")]
+    threads: Option<usize>,
+    #[clap(long, help="Write a binary genotype cache (use a .gfc suffix) that later runs can take as --file-path.")]
+    write_cache: Option<String>,
+    #[clap(long, requires="write-cache", help="Only convert the input to the --write-cache file and exit, no forest arguments are needed.")]
+    convert_only: bool,
+    #[clap(long, help="Remove variants with minor allele frequency below this value (minor carrier frequency for 0/1 genotypes).")]
+    min_maf: Option<f64>,
+    #[clap(long, help="Remove variants with minor allele count below this value (minor carrier count for 0/1 genotypes).")]
+    min_mac: Option<usize>,
+    #[clap(long, help="Remove variants with a missing call rate above this fraction.")]
+    max_variant_missing: Option<f64>,
+    #[clap(long, help="Remove variants with a Hardy-Weinberg exact test p-value below this value (0/1/2 dosages only).")]
+    min_hwe_p: Option<f64>,
+    #[clap(long, help="Remove monomorphic variants.")]
+    remove_monomorphic: bool,
+    #[clap(long, help="Remove samples with a missing call rate above this fraction.")]
+    max_sample_missing: Option<f64>,
+    #[clap(long, help="File of sample IDs to keep (one per line).")]
+    keep: Option<String>,
+    #[clap(long, help="File of sample IDs to remove (one per line).")]
+    remove: Option<String>,
+    #[clap(long, help="File of variant IDs to keep (one per line).")]
+    extract: Option<String>,
+    #[clap(long, help="File of variant IDs to remove (one per line).")]
+    exclude: Option<String>,
+    #[clap(long, multiple_occurrences(true), help="Keep only variants in this region (chr:start-end or chr), may be repeated.")]
+    region: Vec<String>,
+    #[clap(long, help="BED file of regions, keep only variants inside them.")]
+    regions_bed: Option<String>,
+    #[clap(long, help="LD prune variants so that no pair within the window has r2 above this value.")]
+    ld_prune_r2: Option<f64>,
+    #[clap(long, default_value="250", help="Window in kb for LD pruning.")]
+    ld_window_kb: u64,
+    #[clap(long, help="Compute this many genotype principal components (from the kinship matrix of the active variants) to correct for population structure.")]
+    pcs: Option<usize>,
+    #[clap(long, conflicts_with="pcs", help="File of precomputed principal components: a header row (id, names), then one row per sample.")]
+    pc_file: Option<String>,
+    #[clap(long, default_value="covariates", possible_values=&["covariates", "residualize", "none"],
+        help="Use the principal components as split candidates forced into every tree, residualize the (continuous) phenotype on them, or neither.")]
+    pc_correction: String,
+    #[clap(long, help="Stratify the sampling of a binary outcome by this many ancestry clusters (k-means on the principal components).")]
+    ancestry_clusters: Option<usize>,
+    #[clap(long, help="Write the principal components (and ancestry clusters) of the samples to this file.")]
+    pcs_out: Option<String>,
+    #[clap(long, help="Regress the continuous phenotype on the covariates in this file (a header row of id and names, then one numeric row per sample) and grow the trees on the residuals.")]
+    covariates: Option<String>,
+    #[clap(long, help="Write the phenotype, fitted value and residual of every sample to this file.")]
+    residuals_out: Option<String>,
+    #[clap(long, help="Clump the final importances into loci, with proxies at r2 of at least this value.")]
+    clump_r2: Option<f64>,
+    #[clap(long, default_value="250", help="Window in kb for clumping.")]
+    clump_kb: u64
 }
 
 
 fn main() {
     let args = Args::parse();
+    if let Err(err) = check_args(&args) {
+        println!("Error in arguments: {}. Quitting now!", err);
+        std::process::exit(1);
+    }
     let filetype: u8 = input_file_type(&args.file_path);
-    let mut data = match filetype {
-        1 => reader::read_matrix_csv(&args.file_path, &",", &args.continuous_outcome),
-        2 => reader::read_matrix_csv(&args.file_path, &"\t", &args.continuous_outcome),
+    let outcome = match (args.continuous_outcome, args.categorical_outcome, args.survival_outcome) {
+        (true, _, _) => matrix::Outcome::Continuous,
+        (_, true, _) => matrix::Outcome::Categorical,
+        (_, _, true) => matrix::Outcome::Survival,
+        _ => matrix::Outcome::Binary
+    };
+    let data = match filetype {
+        1 => Ok(reader::read_matrix_csv(&args.file_path, ",", &outcome, &args.header)),
+        2 => Ok(reader::read_matrix_csv(&args.file_path, "\t", &outcome, &args.header)),
+        3 => reader::read_matrix_cache(&args.file_path, &outcome),
         _ => panic!("Filetype not supported!"),
     };
-    let variants = match filetype {
-        1 => reader::read_variant_table(&args.variant_file_path, &",", &data.n_genotypes),
-        2 => reader::read_variant_table(&args.variant_file_path, &"\t", &data.n_genotypes),
+    let mut data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Error reading genotype cache: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    let header_ids = match (args.header, filetype) {
+        (true, 1) => Some(reader::read_matrix_header(&args.file_path, ",", &outcome)),
+        (true, 2) => Some(reader::read_matrix_header(&args.file_path, "\t", &outcome)),
+        _ => None
+    }.transpose();
+    let variants = match (filetype, header_ids) {
+        (_, Err(err)) => Err(err),
+        (1, Ok(ids)) => reader::read_variant_table(&args.variant_file_path, ",", &data.n_genotypes, ids),
+        (2, Ok(ids)) => reader::read_variant_table(&args.variant_file_path, "\t", &data.n_genotypes, ids),
+        (3, _) => reader::read_cache_variants(&args.file_path),
         _ => panic!("Filetype not supported!"),
     };
-    utils::make_thread_pool(args.threads);
+    let mut variants = match variants {
+        Ok(variants) => variants,
+        Err(err) => {
+            println!("Error in variant table: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(cache_path) = &args.write_cache {
+        match reader::write_matrix_cache(cache_path, &data, &variants) {
+            Ok(_) => eprintln!("Wrote genotype cache to {}", cache_path),
+            Err(err) => {
+                println!("Error writing genotype cache: {}. Quitting now!", err);
+                std::process::exit(1);
+            }
+        }
+        if args.convert_only {
+            return
+        }
+    }
+    // required by clap unless converting only
+    let (n_iter, z_keep) = (args.n_iter.unwrap(), args.z_keep.unwrap());
+    utils::make_thread_pool(args.threads.unwrap());
+    match make_balance(&args, &data).and_then(|b| data.set_balance(b)) {
+        Ok(_) => (),
+        Err(err) => {
+            println!("Error in sample balancing: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    }
+    let subset_opts = match make_subset_options(&args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            println!("Error reading subset files: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    let mut report = filters::FilterReport::new();
+    filters::subset(&mut data, &variants, &subset_opts, &mut report);
+    let filter_opts = filters::FilterOptions {
+        min_maf: args.min_maf,
+        min_mac: args.min_mac,
+        max_variant_missing: args.max_variant_missing,
+        min_hwe_p: args.min_hwe_p,
+        remove_monomorphic: args.remove_monomorphic,
+        max_sample_missing: args.max_sample_missing
+    };
+    if let Err(err) = filters::apply(&mut data, &filter_opts, &mut report) {
+        println!("Error in filtering: {}. Quitting now!", err);
+        std::process::exit(1);
+    }
+    if !report.rows.is_empty() {
+        report.print();
+        eprintln!("{} variants and {} samples remain after filtering", data.genotype_indices().len(), data.sample_indices().len());
+    }
+    if let Some(r2) = args.ld_prune_r2 {
+        let n_pruned = ld::prune(&mut data, &variants, args.ld_window_kb * 1000, r2);
+        eprintln!("LD pruning removed {} variants, {} remain", n_pruned, data.genotype_indices().len());
+    }
+    let pcs = match (&args.pc_file, args.pcs) {
+        (Some(path), _) => Some(reader::read_covariates(path, &data.ids)),
+        (None, Some(n)) => {
+            eprintln!("Computing {} genotype principal components", n);
+            Some(structure::principal_components(&data, n))
+        },
+        _ => None
+    };
+    let pcs = match pcs.transpose() {
+        Ok(pcs) => pcs,
+        Err(err) => {
+            println!("Error in principal components: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    match (&pcs, args.ancestry_clusters) {
+        (Some(pcs), _) => match correct_structure(&args, &mut data, &mut variants, pcs) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("Error in population structure correction: {}. Quitting now!", err);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(_)) => {
+            println!("Error: --ancestry-clusters needs --pcs or --pc-file. Quitting now!");
+            std::process::exit(1);
+        },
+        _ => ()
+    }
+    let covariates = match &args.covariates {
+        Some(path) => match reader::read_covariates(path, &data.ids) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                println!("Error reading covariates: {}. Quitting now!", err);
+                std::process::exit(1);
+            }
+        },
+        None => None
+    };
+    // the covariates and (with --pc-correction residualize) the principal components are fitted together
+    let adjustment = match (covariates, pcs.filter(|_| args.pc_correction == "residualize")) {
+        (Some(c), Some(p)) => Some(c.join(&p)),
+        (c, p) => c.or(p)
+    };
+    if let Some(adjustment) = &adjustment {
+        match residualize_phenotype(&args, &mut data, adjustment) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("Error in phenotype residualization: {}. Quitting now!", err);
+                std::process::exit(1);
+            }
+        }
+    }
     eprintln!("Growing initial forest");
+    let mtry_mode = match args.mtry_mode.as_str() {
+        "per-node" => forest::MtryMode::PerNode,
+        _ => forest::MtryMode::PerTree
+    };
+    let criterion = match make_criterion(&args, outcome) {
+        Ok(c) => c,
+        Err(err) => {
+            println!("Error choosing the split criterion: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    let selection = match args.split_selection.as_str() {
+        "conditional" => tree::SplitSelection::ConditionalInference { alpha: args.ci_alpha, permutations: args.ci_permutations },
+        "extra-trees" => tree::SplitSelection::ExtraTrees { n_candidates: args.extra_candidates },
+        _ => tree::SplitSelection::Impurity
+    };
     let hp = forest::HyperParameters {
-        n_tree: args.n_tree, 
-        mtry: args.mtry, 
-        max_depth: args.max_depth, 
-        subj_fraction: args.subj_fraction,
-        continuous_outcome: args.continuous_outcome
+        n_tree: args.n_tree.unwrap(), 
+        mtry: args.mtry.unwrap(), 
+        max_depth: args.max_depth.unwrap(), 
+        subj_fraction: args.subj_fraction.unwrap(),
+        outcome,
+        bootstrap: args.bootstrap,
+        mtry_mode,
+        criterion,
+        selection,
+        stopping: tree::StoppingRules {
+            min_node_size: args.min_node_size,
+            min_leaf_size: args.min_leaf_size,
+            min_impurity_decrease: args.min_impurity_decrease,
+            max_leaves: args.max_leaves
+        }
     };
     let hp2 = forest::HyperParameters {
-        n_tree: args.n_tree_2, 
-        mtry: args.mtry_2, 
-        max_depth: args.max_depth_2, 
-        subj_fraction: args.subj_fraction_2,
-        continuous_outcome: args.continuous_outcome
+        n_tree: args.n_tree_2.unwrap(), 
+        mtry: args.mtry_2.unwrap(), 
+        max_depth: args.max_depth_2.unwrap(), 
+        subj_fraction: args.subj_fraction_2.unwrap(),
+        outcome,
+        bootstrap: args.bootstrap,
+        mtry_mode,
+        criterion,
+        selection,
+        stopping: tree::StoppingRules {
+            min_node_size: args.min_node_size_2,
+            min_leaf_size: args.min_leaf_size_2,
+            min_impurity_decrease: args.min_impurity_decrease_2,
+            max_leaves: args.max_leaves_2
+        }
     };
-    let mut f = forest::Forest::new(hp);
+    if !args.tune_param.is_empty() {
+        let base = cv::RunSettings { hp, hp2, z_keep, n_iter };
+        run_tuning(&args, &data, &base);
+        return
+    }
+    if let Some(k) = args.cv_folds {
+        if k < 2 || k > data.sample_indices().len() {
+            println!("Error: the number of folds must be between 2 and the number of samples. Quitting now!");
+            std::process::exit(1);
+        }
+        let settings = cv::RunSettings { hp, hp2, z_keep, n_iter };
+        let folds = cv::make_folds(&data, k, args.cv_stratified);
+        match cv::cross_validate(&mut data, &settings, &folds) {
+            Ok(results) => cv::print_results(&results),
+            Err(err) => {
+                println!("Error in cross-validation: {}. Quitting now!", err);
+                std::process::exit(1);
+            }
+        }
+        return
+    }
+    // Trees are only retained when they are going to be written out or used for prediction
+    let want_proximity = args.proximity_out.is_some() || args.mds_out.is_some() || args.clusters_out.is_some();
+    let keep_trees = args.output_forest || args.predictions_out.is_some() || args.trees_json.is_some() || args.trees_dot.is_some() || want_proximity;
+    // the selection forest is only the final one without iterations
+    let mut f = forest::Forest::new(hp, keep_trees && n_iter == 0);
+    f.set_track_oob(args.oob_error || args.stop_on_oob_increase);
     match f.grow(&data) {
         Ok(_) => (),
         Err(err) => {
@@ -87,40 +452,371 @@ fn main() {
         }
     }
     f.print_var_importance(&variants);
-    let k_vars = f.keep_vars(args.z_keep);
+    if let Some(err) = f.oob_error(&data) {
+        println!("#OOB_ERROR\t{:?}", err);
+    }
+    let convergence = match (args.converge_spearman, args.converge_jaccard) {
+        (None, None) => None,
+        (min_spearman, min_jaccard) => Some(stability::Convergence { min_spearman, min_jaccard, rounds: args.converge_rounds })
+    };
+    let mut tracker = stability::Tracker::new(args.stability_top, convergence);
+    let mut aggregate = aggregate::Aggregate::new();
+    let schedule = prune::Schedule {
+        rule: match (args.prune_fraction, args.prune_z) {
+            (Some(fraction), _) => Some(prune::Rule::Fraction(fraction)),
+            (_, Some(z)) => Some(prune::Rule::ZScore(z)),
+            _ => None
+        },
+        target: args.target_variants,
+        stop_on_oob: args.stop_on_oob_increase
+    };
+    let mut previous_oob: Option<f64> = None;
+    tracker.update(&f.get_var_importances(), data.genotype_indices());
+    let k_vars = f.keep_vars(z_keep);
     eprintln!("Keeping {:?} variants and initiating iterative grow and prune.", &k_vars.len());
-    &data.set_genotype_indices(k_vars);
-    f.update_hyperparameters(hp2);
-    for n in 1..&args.n_iter + 1 {
-        eprintln!("Growing forest {:?} of {:?}", n, &args.n_iter);
-        match f.grow(&data) {
+    let mut eliminations = prune::Eliminations::new(&k_vars);
+    data.set_genotype_indices(k_vars);
+    let mut previous_active: Vec<usize> = data.genotype_indices().to_vec();
+    for n in 1..n_iter + 1 {
+        eprintln!("Growing forest {:?} of {:?}", n, n_iter);
+        // with the OOB stop the previous forest is kept until the new one is accepted,
+        // otherwise its trees are dropped before growing the next one
+        if !schedule.stop_on_oob {
+            f.drop_trees();
+        }
+        let mut grown = forest::Forest::new(hp2, keep_trees);
+        grown.set_track_oob(args.oob_error || args.stop_on_oob_increase);
+        match grown.grow(&data) {
             Ok(_) => (),
             Err(err) => {
                 println!("Error in iteration {:?}: {}; breaking and returning results!", n, err);
+                if !schedule.stop_on_oob {
+                    f = grown;
+                }
                 break
             }
         }
+        let oob = grown.oob_error(&data);
+        if let (true, Some(prev), Some(err)) = (schedule.stop_on_oob, previous_oob, oob) {
+            if err > prev {
+                eprintln!("OOB error rose from {:?} to {:?} at iteration {:?}, stopping and keeping iteration {:?}.", prev, err, n, n - 1);
+                eliminations.restore(n - 1);
+                data.set_genotype_indices(std::mem::take(&mut previous_active));
+                break
+            }
+        }
+        f = grown;
+        aggregate.add_forest(&f, data.genotype_indices());
         println!("## ITERATION: {}", n);
         println!("#OVERALL_IMPORTANCE");
         f.print_var_importance(&variants);
+        if let Some(err) = oob {
+            println!("#OOB_ERROR\t{:?}", err);
+        }
+        if let Some(s) = tracker.update(&f.get_var_importances(), data.genotype_indices()) {
+            stability::print_stability(&s, tracker.top, &variants);
+        }
         if args.output_forest {
             //Output trees
             for tree in f.trees.as_ref().unwrap() {
                 println!("#TREE");
-                tree.print(&0, "0");
+                println!("#var\tparent\tside\tscore\tid\tparent_id\t{}", variants::DESCRIBE_HEADER);
+                tree.print(&0, "0", &variants);
             };
         }
+        if tracker.converged() && n < n_iter {
+            eprintln!("Importance ranking converged at iteration {:?}, stopping early.", n);
+            break
+        }
+        previous_oob = oob;
+        if schedule.reached_target(data.genotype_indices().len()) {
+            eprintln!("Reached {:?} variants at iteration {:?}, stopping.", data.genotype_indices().len(), n);
+            break
+        }
+        if n < n_iter && schedule.rule.is_some() {
+            let active = data.genotype_indices().to_vec();
+            let survivors = schedule.survivors(&f.get_var_importances(), &active);
+            eliminations.record(n, &active, &survivors);
+            eprintln!("Pruned {:?} variants, {:?} remain.", active.len() - survivors.len(), survivors.len());
+            data.set_genotype_indices(survivors);
+            previous_active = active;
+        }
     }
+    if schedule.rule.is_some() {
+        eliminations.print(&variants);
+    }
+    if !aggregate.is_empty() {
+        let summaries = aggregate.summarize(args.importance_bootstrap, args.importance_ci);
+        aggregate::update_variants(&summaries, &mut variants);
+        aggregate::print_summaries(&summaries, &variants, args.importance_ci);
+    }
+    if let Some(path) = &args.predictions_out {
+        let subjects = data.sample_indices().to_vec();
+        let predictions = f.predict(&data, &subjects);
+        match reader::write_predictions(path, &data, &subjects, &predictions) {
+            Ok(_) => eprintln!("Wrote predictions to {}", path),
+            Err(err) => println!("Error writing predictions: {}", err)
+        }
+    }
+    if let Some(path) = &args.trees_json {
+        match export::write_trees_json(path, f.trees.as_deref().unwrap_or(&[]), &variants) {
+            Ok(_) => eprintln!("Wrote trees to {}", path),
+            Err(err) => println!("Error writing trees: {}", err)
+        }
+    }
+    if let Some(path) = &args.trees_dot {
+        match export::write_trees_dot(path, f.trees.as_deref().unwrap_or(&[]), &variants) {
+            Ok(_) => eprintln!("Wrote trees to {}", path),
+            Err(err) => println!("Error writing trees: {}", err)
+        }
+    }
+    if want_proximity {
+        eprintln!("Computing sample proximities");
+        let prox = f.proximity(&data, args.proximity_mode == "oob").unwrap();
+        if let Some(path) = &args.proximity_out {
+            match prox.write(path, &data) {
+                Ok(_) => eprintln!("Wrote proximities to {}", path),
+                Err(err) => println!("Error writing proximities: {}", err)
+            }
+        }
+        if let Some(path) = &args.mds_out {
+            let header: Vec<String> = (1..=args.mds_dims).map(|d| format!("mds_{}", d)).collect();
+            let rows: Vec<String> = prox.mds(args.mds_dims).iter()
+                .map(|c| c.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("\t"))
+                .collect();
+            match proximity::write_subject_table(path, &data, &prox.subjects, &header.join("\t"), &rows) {
+                Ok(_) => eprintln!("Wrote MDS coordinates to {}", path),
+                Err(err) => println!("Error writing MDS coordinates: {}", err)
+            }
+        }
+        if let Some(path) = &args.clusters_out {
+            let rows: Vec<String> = prox.cluster(args.n_clusters).iter().map(|c| c.to_string()).collect();
+            match proximity::write_subject_table(path, &data, &prox.subjects, "cluster", &rows) {
+                Ok(_) => eprintln!("Wrote sample clusters to {}", path),
+                Err(err) => println!("Error writing sample clusters: {}", err)
+            }
+        }
+    }
+    if let Some(r2) = args.clump_r2 {
+        let clumps = ld::clump(&data, &variants, &f.get_var_importances(), args.clump_kb * 1000, r2);
+        ld::print_clumps(&clumps, &variants);
+    }
+
+}
+
+/// Search the settings given with --tune-param, print the ranking and write the best configuration
+fn run_tuning(args: &Args, data: &matrix::GenoMatrix, base: &cv::RunSettings) {
+    let search = match args.tune_search.as_str() {
+        "random" => tune::Search::Random(args.tune_samples),
+        _ => tune::Search::Grid
+    };
+    let candidates = tune::Metric::parse(&args.tune_metric)
+        .and_then(|m| m.check(data.outcome).map(|_| m))
+        .and_then(|m| {
+            let params = args.tune_param.iter().map(|p| tune::Param::parse(p)).collect::<Result<Vec<tune::Param>, String>>()?;
+            let candidates = tune::configurations(&params, &search)?.into_iter()
+                .map(|c| tune::apply(base, &c).map(|s| (c, s)))
+                .collect::<Result<Vec<(tune::Config, cv::RunSettings)>, String>>()?;
+            Ok((m, candidates))
+        });
+    let (metric, candidates) = match candidates {
+        Ok(c) => c,
+        Err(err) => {
+            println!("Error in tuning settings: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    let folds = match metric {
+        tune::Metric::OobError => Vec::new(),
+        _ => {
+            let k = args.cv_folds.unwrap_or(5);
+            if k < 2 || k > data.sample_indices().len() {
+                println!("Error: the number of folds must be between 2 and the number of samples. Quitting now!");
+                std::process::exit(1);
+            }
+            cv::make_folds(data, k, args.cv_stratified)
+        }
+    };
+    eprintln!("Evaluating {} configurations", candidates.len());
+    let results = match tune::tune(data, candidates, metric, &folds) {
+        Ok(r) => r,
+        Err(err) => {
+            println!("Error in tuning: {}. Quitting now!", err);
+            std::process::exit(1);
+        }
+    };
+    tune::print_results(&results, metric);
+    if let Some(best) = results.first() {
+        println!("#BEST\t{}", tune::settings_args(&best.settings));
+        if let Some(path) = &args.tune_out {
+            match tune::write_best(path, &best.settings) {
+                Ok(_) => eprintln!("Wrote best configuration to {}", path),
+                Err(err) => println!("Error writing best configuration: {}", err)
+            }
+        }
+    }
+}
+
+/// Reject argument values that are out of range
+fn check_args(args: &Args) -> Result<(), String> {
+    for (name, fraction) in [("--mtry", args.mtry), ("--subj-fraction", args.subj_fraction), ("--mtry-2", args.mtry_2), ("--subj-fraction-2", args.subj_fraction_2)] {
+        if let Some(fraction) = fraction {
+            if !(fraction > 0. && fraction <= 1.) {
+                return Err(format!("{} must be in (0, 1], got {}", name, fraction))
+            }
+        }
+    }
+    for (name, n) in [("--n-tree", args.n_tree), ("--max-depth", args.max_depth), ("--n-tree-2", args.n_tree_2), ("--max-depth-2", args.max_depth_2)] {
+        if let Some(n) = n {
+            if n <= 0 {
+                return Err(format!("{} must be greater than 0, got {}", name, n))
+            }
+        }
+    }
+    if let Some(z) = args.z_keep {
+        if z.is_nan() || z < 0. {
+            return Err(format!("--z-keep must be at least 0, got {}", z))
+        }
+    }
+    if let Some(fraction) = args.prune_fraction {
+        if !(fraction > 0. && fraction < 1.) {
+            return Err(format!("--prune-fraction must be between 0 and 1, got {}", fraction))
+        }
+    }
+    if let Some(z) = args.prune_z {
+        if !z.is_finite() {
+            return Err(format!("--prune-z must be a finite number, got {}", z))
+        }
+    }
+    if !(args.importance_ci > 0. && args.importance_ci < 1.) {
+        return Err(format!("--importance-ci must be between 0 and 1, got {}", args.importance_ci))
+    }
+    Ok(())
+}
 
+/// Apply the principal components: forced covariates or residualized phenotype, ancestry strata and output
+fn correct_structure(args: &Args, data: &mut matrix::GenoMatrix, variants: &mut Vec<variants::Variant>, pcs: &matrix::Covariates) -> Result<(), String> {
+    let subjects = data.sample_indices().to_vec();
+    let missing = subjects.iter().filter(|s| pcs.values[**s].iter().any(|v| !v.is_finite())).count();
+    if missing > 0 {
+        return Err(format!("{} samples have no principal components", missing))
+    }
+    if args.pc_correction == "covariates" {
+        data.add_covariate_splits(pcs);
+        variants.extend(pcs.names.iter().map(|n| variants::Variant::new(n.to_string())));
+        eprintln!("Added {} principal components as split candidates", pcs.names.len());
+    }
+    let clusters = args.ancestry_clusters.map(|k| structure::ancestry_clusters(data, pcs, k));
+    if let Some(labels) = &clusters {
+        data.set_strata(labels.to_vec())?;
+    }
+    if let Some(path) = &args.pcs_out {
+        let mut header = pcs.names.to_vec();
+        if clusters.is_some() {
+            header.push(String::from("cluster"));
+        }
+        let rows: Vec<String> = subjects.iter().map(|s| {
+            let mut fields: Vec<String> = pcs.values[*s].iter().map(|v| v.to_string()).collect();
+            if let Some(labels) = &clusters {
+                fields.push((labels[*s] + 1).to_string());
+            }
+            fields.join("\t")
+        }).collect();
+        proximity::write_subject_table(path, data, &subjects, &header.join("\t"), &rows).map_err(|e| format!("{}: {}", path, e))?;
+        eprintln!("Wrote principal components to {}", path);
+    }
+    Ok(())
+}
+
+/// Grow the trees on the residuals of the phenotype regressed on the covariates
+/// Prints the coefficients and writes the residuals of the active samples if asked to
+fn residualize_phenotype(args: &Args, data: &mut matrix::GenoMatrix, covariates: &matrix::Covariates) -> Result<(), String> {
+    if data.outcome != matrix::Outcome::Continuous {
+        return Err(String::from("residualizing needs a continuous outcome"))
+    }
+    let subjects = data.sample_indices().to_vec();
+    let missing = subjects.iter().filter(|s| covariates.values[**s].iter().any(|v| !v.is_finite())).count();
+    if missing > 0 {
+        return Err(format!("{} samples have missing covariates", missing))
+    }
+    let coefficients = data.residualize(covariates)?;
+    eprintln!("Residualized the phenotype on {} covariates", covariates.names.len());
+    println!("#RESIDUALIZATION");
+    println!("#term\tcoefficient");
+    for (name, b) in std::iter::once("intercept").chain(covariates.names.iter().map(|n| n.as_str())).zip(coefficients.iter()) {
+        println!("{}\t{:?}", name, b);
+    }
+    if let Some(path) = &args.residuals_out {
+        let rows: Vec<String> = subjects.iter().map(|s| {
+            let (p, r) = (data.phenotypes[*s], data.response()[*s]);
+            format!("{}\t{}\t{}", p, p - r, r)
+        }).collect();
+        proximity::write_subject_table(path, data, &subjects, "phenotype\tfitted\tresidual", &rows).map_err(|e| format!("{}: {}", path, e))?;
+        eprintln!("Wrote residuals to {}", path);
+    }
+    Ok(())
+}
+
+/// Build the subject sampling strategy from the command line
+fn make_balance(args: &Args, data: &matrix::GenoMatrix) -> Result<matrix::Balance, String> {
+    match args.balance.as_str() {
+        "none" => Ok(matrix::Balance::None),
+        "ratio" => match args.case_ratio {
+            Some(r) => Ok(matrix::Balance::Ratio(r)),
+            None => Err(String::from("--balance ratio needs --case-ratio"))
+        },
+        "downsample" => Ok(matrix::Balance::Downsample),
+        "weights" => match &args.sample_weights {
+            Some(p) => Ok(matrix::Balance::Weights(reader::read_sample_weights(p, &data.ids)?)),
+            None => Err(String::from("--balance weights needs --sample-weights"))
+        },
+        "quantile" => Ok(matrix::Balance::Quantiles(args.quantile_strata)),
+        _ => Ok(matrix::Balance::Phenotype)
+    }
+}
+
+/// Resolve the split criterion, the default depends on the outcome type
+fn make_criterion(args: &Args, outcome: matrix::Outcome) -> Result<criterion::Criterion, String> {
+    let c = match &args.split_criterion {
+        Some(name) => criterion::Criterion::parse(name)?,
+        None => criterion::Criterion::default_for(outcome)
+    };
+    c.check(outcome)?;
+    Ok(c)
+}
+
+/// Read the sample, variant and region lists used to subset the matrix
+fn make_subset_options(args: &Args) -> Result<filters::SubsetOptions, String> {
+    let read_ids = |path: &Option<String>| -> Result<Option<HashSet<String>>, String> {
+        match path {
+            Some(p) => reader::read_id_list(p).map(Some).map_err(|e| format!("{}: {}", p, e)),
+            None => Ok(None)
+        }
+    };
+    let mut regions: Vec<variants::Region> = Vec::new();
+    for r in &args.region {
+        regions.push(variants::Region::parse(r)?);
+    }
+    if let Some(p) = &args.regions_bed {
+        regions.append(&mut reader::read_bed(p).map_err(|e| format!("{}: {}", p, e))?);
+    }
+    Ok(filters::SubsetOptions {
+        keep_samples: read_ids(&args.keep)?,
+        remove_samples: read_ids(&args.remove)?,
+        extract: read_ids(&args.extract)?,
+        exclude: read_ids(&args.exclude)?,
+        regions: if regions.is_empty() { None } else { Some(regions) }
+    })
 }
 
 /// Determine input filetype based on suffix
 fn input_file_type(filename: &str) -> u8 {
-    let file_split: Vec<_> = filename.split(".").map(|s| s).collect();
+    let file_split: Vec<_> = filename.split('.').collect();
     let suffix = file_split[file_split.len() - 1];
     match suffix {
         "csv" => 1,
         "tsv" => 2,
+        "gfc" => 3,
         "gz" => 9,
         _ => 0,
     }
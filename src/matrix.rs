@@ -1,16 +1,139 @@
+use crate::statistics;
+use crate::tree;
+
 use sprs::{CsMat, Shape, TriMat};
 use rand::{Rng, thread_rng};
+use rand::rngs::ThreadRng;
 use rand::seq::SliceRandom;
+use rand::distributions::{Distribution, WeightedIndex};
+use memmap2::Mmap;
+use std::collections::HashMap;
 use std::fs::File;
+use std::sync::Arc;
+
+/// Genotype codes handed out by reference from packed storage
+static GENOTYPE_CODES: [u8; 4] = [0, 1, 2, 3];
+
+/// Code used for a missing genotype call (NA, . or empty in the input matrix)
+pub const MISSING_GENOTYPE: u8 = 3;
+
+/// Type of outcome in the phenotype column
+/// Binary outcomes are coded 0/1, categorical outcomes as class labels 0..k-1
+/// Survival outcomes have two columns, time and then event (1) or censoring (0)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Outcome {
+    Binary,
+    Continuous,
+    Categorical,
+    Survival
+}
 
+impl Outcome {
+    /// Number of columns before the genotypes (id and the outcome columns)
+    pub fn leading_columns(&self) -> usize {
+        match self {
+            Outcome::Survival => 3,
+            _ => 2
+        }
+    }
+}
+
+/// Strategy for balancing the subjects sampled for each tree
+/// Phenotype = binary outcomes weighted towards balance by phenotype frequency, categorical outcomes
+///     stratified by class, no weighting otherwise (the default)
+/// None = every subject sampled with the same probability
+/// Ratio = cases and controls drawn at a target case:control ratio (binary only)
+/// Downsample = every class drawn down to the size of the smallest class (binary / categorical)
+/// Weights = inclusion probability proportional to a per-sample weight (indexed like GenoMatrix.ids)
+/// Quantiles = stratified by outcome quantile (continuous only)
+#[derive(Clone)]
+pub enum Balance {
+    Phenotype,
+    None,
+    Ratio(f64),
+    Downsample,
+    Weights(Vec<f64>),
+    Quantiles(usize)
+}
+
+/// Maximum number of time points used for the cumulative hazard of survival outcomes
+const MAX_TIME_GRID: usize = 50;
+
+/// Clones share the genotype storage, so copies with their own active subjects and variants are cheap
+#[derive(Clone)]
 pub struct GenoMatrix {
     pub ids: Vec<String>,
     pub phenotypes: Vec<f64>,
     pub n_subjects: f64,
     pub n_genotypes: f64,
+    pub outcome: Outcome,
+    pub n_classes: usize, // number of outcome classes, 0 if continuous
+    pub events: Vec<f64>, // event indicator for survival outcomes (phenotypes holds the times), otherwise empty
+    pub time_grid: Vec<f64>, // event times at which cumulative hazards are estimated (survival only)
     pheno_weight: f64, // weight to use for selecting phenotype (e.g. 0.5 would be balanced...)
-    genotypes: CsMat<u8>,
-    genotype_indices: Vec<usize>
+    balance: Balance,
+    genotypes: Arc<Genotypes>,
+    genotype_indices: Vec<usize>, // active variants
+    sample_indices: Vec<usize>, // active subjects
+    covariate_codes: Vec<Vec<u8>>, // covariates coded 0/1 per row, split on after the genotype columns
+    response: Option<Vec<f64>>, // adjusted outcome the trees grow on, the phenotypes if None
+    adjustment: Option<Arc<Covariates>>, // covariates the response was residualized on
+    strata: Option<Vec<usize>> // ancestry stratum of each row for subject sampling
+}
+
+/// Covariate values of the subjects, values[row] holds one value per covariate for each matrix row
+/// NaN where a subject has no value
+#[derive(Clone)]
+pub struct Covariates {
+    pub names: Vec<String>,
+    pub values: Vec<Vec<f64>>
+}
+
+impl Covariates {
+
+    /// The covariates of both tables side by side (rows must follow the same matrix)
+    pub fn join(mut self, other: &Covariates) -> Self {
+        self.names.extend(other.names.iter().cloned());
+        for (row, extra) in self.values.iter_mut().zip(other.values.iter()) {
+            row.extend(extra.iter().copied());
+        }
+        self
+    }
+}
+
+/// Backing storage for the genotypes
+/// Sparse when parsed from a text matrix, packed when memory-mapped from a binary cache
+enum Genotypes {
+    Sparse(CsMat<u8>),
+    Packed(PackedGenotypes)
+}
+
+/// Memory-mapped genotypes, 2 bits per genotype
+/// Stored variant-major, each variant padded to a whole byte
+pub struct PackedGenotypes {
+    mmap: Mmap,
+    offset: usize, // start of the genotype block in the mapped file
+    col_bytes: usize // bytes per variant
+}
+
+impl PackedGenotypes {
+    pub fn new(mmap: Mmap, offset: usize, n_subjects: usize) -> Self {
+        PackedGenotypes {
+            mmap,
+            offset,
+            col_bytes: packed_col_bytes(n_subjects)
+        }
+    }
+
+    fn get(&self, s: usize, g: usize) -> &u8 {
+        let byte = self.mmap[self.offset + g * self.col_bytes + s / 4];
+        &GENOTYPE_CODES[((byte >> ((s % 4) * 2)) & 0b11) as usize]
+    }
+}
+
+/// Number of bytes needed to pack one variant for n_subjects
+pub fn packed_col_bytes(n_subjects: usize) -> usize {
+    n_subjects.div_ceil(4)
 }
 
 pub struct GenoMatrixSlice {
@@ -18,109 +141,541 @@ pub struct GenoMatrixSlice {
     pub genotype_ids: Vec<usize>,
 }
 
+/// Genotypes of a slice, by position in its variants and subject draws
+/// The columns are either copied out of the matrix once (columns), or looked up in the matrix
+/// whenever a tree asks for them, so a slice over many variants never holds them all
+pub struct SliceGenotypes<'a> {
+    gm: &'a GenoMatrix,
+    subjects: Vec<usize>,
+    variants: Vec<usize>,
+    columns: Option<Vec<Vec<u8>>>
+}
+
+impl<'a> SliceGenotypes<'a> {
+
+    /// Genotypes of the variant at position c for the given subject draws
+    pub fn column(&self, c: usize, rows: &[usize]) -> Vec<&u8> {
+        match &self.columns {
+            Some(columns) => rows.iter().map(|r| &columns[c][*r]).collect(),
+            None => rows.iter().map(|r| self.gm.genotype(self.subjects[*r], self.variants[c])).collect()
+        }
+    }
+}
+
 impl GenoMatrix {
     
-    pub fn new(rdr: &mut csv::Reader<File>, mat_size: Shape, continuous_outcome: &bool) -> Self {
-        let pheno_weight: f64;
+    pub fn new(rdr: &mut csv::Reader<File>, mat_size: Shape, outcome: &Outcome) -> Self {
         let mut row_ids: Vec<String> = Vec::new();
         let mut phenotypes: Vec<f64> = Vec::new();
+        let mut events: Vec<f64> = Vec::new();
         let mut geno_mat = TriMat::new(mat_size);
-        let mut rownum = 0;
-        for result in rdr.records() {
-            let mut colnum = 2;
+        let lead = outcome.leading_columns();
+        for (rownum, result) in rdr.records().enumerate() {
+            let mut colnum = lead;
             let record = result.unwrap();
             row_ids.push(record[0].to_string());
             phenotypes.push(record[1].parse::<f64>().unwrap());
+            if *outcome == Outcome::Survival {
+                events.push(record[2].parse::<f64>().unwrap());
+            }
             loop {
-                if colnum == mat_size.1 + 2 {
+                if colnum == mat_size.1 + lead {
                     break
                 }
-                geno_mat.add_triplet(rownum, colnum - 2, record[colnum].parse::<u8>().unwrap());
+                geno_mat.add_triplet(rownum, colnum - lead, parse_genotype(&record[colnum]));
                 colnum += 1;
             }
-            rownum += 1
-        }
-        if *continuous_outcome {
-            pheno_weight = -1.
-        } else {
-            pheno_weight = phenotypes.iter().sum::<f64>() as f64 / mat_size.0 as f64;
         }
+        let (pheno_weight, n_classes) = summarize_outcome(&phenotypes, &events, outcome);
+        let time_grid = make_time_grid(&phenotypes, &events);
+        let sample_indices = coded_samples(&phenotypes, outcome);
         GenoMatrix{
             ids: row_ids, 
-            phenotypes: phenotypes,
+            phenotypes,
             n_subjects: mat_size.0 as f64,
             n_genotypes: mat_size.1 as f64,
-            genotypes: geno_mat.to_csr(),
-            pheno_weight: pheno_weight,
-            genotype_indices: (0..mat_size.1).collect()
+            outcome: *outcome,
+            n_classes,
+            events,
+            time_grid,
+            genotypes: Arc::new(Genotypes::Sparse(geno_mat.to_csr())),
+            pheno_weight,
+            balance: Balance::Phenotype,
+            genotype_indices: (0..mat_size.1).collect(),
+            sample_indices,
+            covariate_codes: Vec::new(),
+            response: None,
+            adjustment: None,
+            strata: None
         }
     }
 
-    pub fn make_slice(&self, var_frac: f64, subj_frac: f64) -> GenoMatrixSlice {
-    //  Note that these are all sampling without replacement
-    //  We don't implement sampling with replacement for this
-    //  Sampling with replacement improves predictive ability of the model
-    //  Which we do not care about.
-    //  Subject selection is auto-weighted for phenotype. 
-    //  Might consider adding an option to disable this or accept 
-    //  external weights (or just specify target ratio)
+    /// Build the matrix around genotypes memory-mapped from a binary cache
+    pub fn from_packed(ids: Vec<String>, phenotypes: Vec<f64>, events: Vec<f64>, n_genotypes: usize, packed: PackedGenotypes, outcome: &Outcome) -> Self {
+        let n_subjects = ids.len();
+        let (pheno_weight, n_classes) = summarize_outcome(&phenotypes, &events, outcome);
+        let time_grid = make_time_grid(&phenotypes, &events);
+        let sample_indices = coded_samples(&phenotypes, outcome);
+        GenoMatrix {
+            ids,
+            phenotypes,
+            n_subjects: n_subjects as f64,
+            n_genotypes: n_genotypes as f64,
+            outcome: *outcome,
+            n_classes,
+            events,
+            time_grid,
+            genotypes: Arc::new(Genotypes::Packed(packed)),
+            pheno_weight,
+            balance: Balance::Phenotype,
+            genotype_indices: (0..n_genotypes).collect(),
+            sample_indices,
+            covariate_codes: Vec::new(),
+            response: None,
+            adjustment: None,
+            strata: None
+        }
+    }
+
+    /// Genotype of subject s at variant g
+    /// Indices past the genotype columns are the coded covariates (see add_covariate_splits)
+    pub fn genotype(&self, s: usize, g: usize) -> &u8 {
+        let n_cols = self.n_genotypes as usize;
+        if g >= n_cols {
+            return &self.covariate_codes[g - n_cols][s]
+        }
+        match self.genotypes.as_ref() {
+            Genotypes::Sparse(m) => m.get(s, g).unwrap(),
+            Genotypes::Packed(p) => p.get(s, g)
+        }
+    }
+
+    pub fn make_slice(&self, var_frac: f64, subj_frac: f64, bootstrap: bool) -> GenoMatrixSlice {
+    //  By default subjects are sampled without replacement,
+    //  predictive ability of the model is not the main goal.
+    //  With bootstrap the same draws are made with replacement,
+    //  so a subject may appear in a slice more than once.
+    //  Subject selection is auto-weighted for phenotype by default,
+    //  see Balance for the other strategies.
 
-        let prob_0 = self.pheno_weight * subj_frac;
-        let prob_1 = (1. - self.pheno_weight) * subj_frac;
-        let mut subjs: Vec<usize> = Vec::new();
-        let mut g_ids: Vec<usize> = Vec::new();
         let mut rng = thread_rng();
-        for s in 0..self.n_subjects as usize {
-            match self.phenotypes[s] {
-                _x if self.pheno_weight == -1.0 => { // happens if pheno is continuous, no weighting applied
-                    if rng.gen_bool(subj_frac) {
-                        subjs.push(s);
-                    }
-                },
-                _x if _x == 0.0 => {
-                    if rng.gen_bool(prob_0) {
-                        subjs.push(s);
-                    }
-                },
-                _x if _x == 1.0 => {
-                    if rng.gen_bool(prob_1) {
-                        subjs.push(s);
-                    }
-                },
-                _ => ()
-            }
-        };
+        let subjs = self.sample_subjects(subj_frac, bootstrap, &mut rng);
+        let mut g_ids: Vec<usize> = Vec::new();
         for g in &self.genotype_indices {
             if rng.gen_bool(var_frac) {
                 g_ids.push(*g);
             };
         };
+        // covariates are forced into every tree, last so the nodes can find them
+        g_ids.extend(self.covariate_indices());
         GenoMatrixSlice {
             subj_ids: subjs,
             genotype_ids: g_ids
         }
     }
-    
-    pub fn get_slice_data(&self, gm: &GenoMatrixSlice) -> (Vec<&f64>, Vec<&f64>, Vec<Vec<&u8>>) {
+
+    /// Select the subjects for one tree according to the balancing strategy
+    fn sample_subjects(&self, subj_frac: f64, replace: bool, rng: &mut ThreadRng) -> Vec<usize> {
+        let mut subjs: Vec<usize> = Vec::new();
+        match (&self.balance, self.outcome) {
+            (Balance::Phenotype, Outcome::Binary) => {
+                // with ancestry strata the phenotype weight is taken within each stratum
+                let stratum_weights = self.stratum_pheno_weights();
+                let probs: Vec<f64> = self.sample_indices.iter().map(|s| {
+                    let weight = match (&self.strata, &stratum_weights) {
+                        (Some(strata), Some(w)) => w[&strata[*s]],
+                        _ => self.pheno_weight
+                    };
+                    match self.phenotypes[*s] {
+                        0. => weight * subj_frac,
+                        1. => (1. - weight) * subj_frac,
+                        _ => 0. // not coded 0/1, such subjects are not active (see coded_samples)
+                    }
+                }).collect();
+                subjs.extend(draw_weighted(&self.sample_indices, &probs, replace, rng));
+            },
+            (Balance::Phenotype, Outcome::Categorical) => {
+                // class-stratified, each class contributes subj_frac of its subjects
+                for class in self.class_groups() {
+                    let n_draw = (class.len() as f64 * subj_frac).round() as usize;
+                    subjs.extend(draw(&class, n_draw, replace, rng));
+                }
+            },
+            (Balance::Phenotype, _) | (Balance::None, _) => {
+                // no weighting applied (always the case for continuous and survival outcomes)
+                let probs = vec![subj_frac; self.sample_indices.len()];
+                subjs.extend(draw_weighted(&self.sample_indices, &probs, replace, rng));
+            },
+            (Balance::Ratio(ratio), _) => {
+                // cases (phenotype 1) : controls (phenotype 0) drawn at the target ratio
+                let classes = self.class_groups();
+                let n_total = self.sample_indices.len() as f64 * subj_frac;
+                let n_cases = (n_total * ratio / (1. + ratio)).round() as usize;
+                let n_controls = (n_total / (1. + ratio)).round() as usize;
+                subjs.extend(draw(&classes[0], n_controls, replace, rng));
+                subjs.extend(draw(&classes[1], n_cases, replace, rng));
+            },
+            (Balance::Downsample, _) => {
+                // every class is drawn down to the size of the smallest class
+                let classes = self.class_groups();
+                let n_min = classes.iter().map(|c| c.len()).filter(|n| *n > 0).min().unwrap_or(0);
+                let n_draw = (n_min as f64 * subj_frac).round() as usize;
+                for class in &classes {
+                    subjs.extend(draw(class, n_draw, replace, rng));
+                }
+            },
+            (Balance::Weights(weights), _) => {
+                // inclusion probability proportional to the subject's weight
+                let total: f64 = self.sample_indices.iter().map(|s| weights[*s]).sum();
+                let scale = subj_frac * self.sample_indices.len() as f64 / total;
+                let probs: Vec<f64> = self.sample_indices.iter().map(|s| weights[*s] * scale).collect();
+                subjs.extend(draw_weighted(&self.sample_indices, &probs, replace, rng));
+            },
+            (Balance::Quantiles(n_strata), _) => {
+                // stratified by outcome quantile, each stratum contributes subj_frac of its subjects
+                for stratum in self.quantile_groups(*n_strata) {
+                    let n_draw = (stratum.len() as f64 * subj_frac).round() as usize;
+                    subjs.extend(draw(&stratum, n_draw, replace, rng));
+                }
+            }
+        };
+        subjs
+    }
+
+    /// Fraction of cases among the active subjects of each ancestry stratum, None without strata
+    fn stratum_pheno_weights(&self) -> Option<HashMap<usize, f64>> {
+        let strata = self.strata.as_ref()?;
+        let mut counts: HashMap<usize, (f64, f64)> = HashMap::new();
+        for s in &self.sample_indices {
+            let c = counts.entry(strata[*s]).or_insert((0., 0.));
+            c.0 += self.phenotypes[*s];
+            c.1 += 1.;
+        }
+        Some(counts.into_iter().map(|(k, (cases, n))| (k, cases / n)).collect())
+    }
+
+    /// Active subjects grouped by outcome class
+    fn class_groups(&self) -> Vec<Vec<usize>> {
+        let mut classes: Vec<Vec<usize>> = vec![Vec::new(); self.n_classes];
+        for &s in &self.sample_indices {
+            classes[self.phenotypes[s] as usize].push(s);
+        }
+        classes
+    }
+
+    /// Active subjects grouped into n_strata strata of (near) equal size by outcome value
+    fn quantile_groups(&self, n_strata: usize) -> Vec<Vec<usize>> {
+        let mut ordered: Vec<usize> = self.sample_indices.to_vec();
+        let response = self.response();
+        ordered.sort_by(|a, b| response[*a].partial_cmp(&response[*b]).unwrap());
+        let n = ordered.len();
+        (0..n_strata).map(|q| ordered[q * n / n_strata..(q + 1) * n / n_strata].to_vec()).collect()
+    }
+
+    /// Set the strategy used to balance subject sampling
+    /// Checks that the strategy fits the outcome type
+    pub fn set_balance(&mut self, balance: Balance) -> Result<(), String> {
+        match (&balance, self.outcome) {
+            (Balance::Ratio(r), Outcome::Binary) if *r > 0. => (),
+            (Balance::Ratio(_), Outcome::Binary) => return Err(String::from("case:control ratio must be positive")),
+            (Balance::Ratio(_), _) => return Err(String::from("a case:control ratio needs a binary outcome")),
+            (Balance::Downsample, Outcome::Binary | Outcome::Categorical) => (),
+            (Balance::Downsample, _) => return Err(String::from("down-sampling needs a binary or categorical outcome")),
+            (Balance::Quantiles(n), Outcome::Continuous) if *n > 0 => (),
+            (Balance::Quantiles(_), _) => return Err(String::from("quantile strata need a continuous outcome and at least one stratum")),
+            (Balance::Weights(w), _) if w.len() != self.ids.len() => return Err(String::from("there must be one weight per sample")),
+            (Balance::Weights(w), _) if w.iter().any(|x| *x < 0. || !x.is_finite()) => return Err(String::from("sample weights must be non-negative")),
+            (Balance::Weights(w), _) if w.iter().sum::<f64>() <= 0. => return Err(String::from("at least one sample weight must be positive")),
+            _ => ()
+        }
+        self.balance = balance;
+        Ok(())
+    }
+
+    /// Gather the data for a tree from a slice
+    /// The shuffled phenotypes are a random permutation of the subjects, for survival outcomes
+    /// the (time, event) pairs are permuted together
+    /// A subject drawn more than once (bootstrap) is gathered once per draw,
+    /// so it counts with its multiplicity in every impurity calculation
+    /// Genotypes are read through genos, see slice_genotypes
+    pub fn get_slice_data<'a>(&'a self, gm: &GenoMatrixSlice, genos: &'a SliceGenotypes<'a>) -> tree::NodeData<'a> {
         let mut rng = thread_rng();
-        let mut g_vec: Vec<Vec<&u8>> = Vec::new();
         let mut p_vec: Vec<&f64> = Vec::new();
+        let mut e_vec: Vec<&f64> = Vec::new();
         for s in &gm.subj_ids {
-            p_vec.push(&self.phenotypes[*s]);
-        };
-        for g in &gm.genotype_ids {
-            let mut gv: Vec<&u8> = Vec::new();
-            for s in &gm.subj_ids {
-                gv.push(self.genotypes.get(*s, *g).unwrap());
+            p_vec.push(&self.response()[*s]);
+            if !self.events.is_empty() {
+                e_vec.push(&self.events[*s]);
             }
-            g_vec.push(gv);
         };
-        let mut pheno2: Vec<&f64> = p_vec.to_vec();
-        pheno2.shuffle(&mut rng);
-        return (p_vec, pheno2, g_vec)
+        let mut perm: Vec<usize> = (0..p_vec.len()).collect();
+        perm.shuffle(&mut rng);
+        let pheno2: Vec<&f64> = perm.iter().map(|i| p_vec[*i]).collect();
+        let event2: Vec<&f64> = if e_vec.is_empty() { Vec::new() } else { perm.iter().map(|i| e_vec[*i]).collect() };
+        tree::NodeData {
+            phenos: p_vec,
+            phenos_shuffle: pheno2,
+            events: e_vec,
+            events_shuffle: event2,
+            rows: (0..gm.subj_ids.len()).collect(),
+            genos
+        }
+    }
+
+    /// Genotypes of a slice for a tree, copied into the slice when materialize is set
+    /// (every node scores every variant of the slice) and looked up otherwise
+    pub fn slice_genotypes(&self, gm: &GenoMatrixSlice, materialize: bool) -> SliceGenotypes<'_> {
+        let columns = materialize.then(|| {
+            gm.genotype_ids.iter().map(|g| gm.subj_ids.iter().map(|s| *self.genotype(*s, *g)).collect()).collect()
+        });
+        SliceGenotypes {
+            gm: self,
+            subjects: gm.subj_ids.to_vec(),
+            variants: gm.genotype_ids.to_vec(),
+            columns
+        }
     }
 
+    /// Set the active variants, covariate indices are left out as they are always used
     pub fn set_genotype_indices(&mut self, variants: Vec<usize>) {
-        self.genotype_indices = variants;
+        let n_cols = self.n_genotypes as usize;
+        self.genotype_indices = variants.into_iter().filter(|g| *g < n_cols).collect();
+    }
+
+    pub fn genotype_indices(&self) -> &Vec<usize> {
+        &self.genotype_indices
+    }
+
+    /// Restrict the subjects used for growing trees
+    /// The phenotype weight is recalculated over the remaining subjects
+    pub fn set_sample_indices(&mut self, samples: Vec<usize>) {
+        if self.outcome == Outcome::Binary && !samples.is_empty() {
+            self.pheno_weight = samples.iter().map(|s| self.phenotypes[*s]).sum::<f64>() / samples.len() as f64;
+        }
+        self.sample_indices = samples;
+    }
+
+    pub fn sample_indices(&self) -> &Vec<usize> {
+        &self.sample_indices
+    }
+
+    /// The outcome the trees grow on: the residual phenotypes after residualize, otherwise the phenotypes
+    pub fn response(&self) -> &Vec<f64> {
+        self.response.as_ref().unwrap_or(&self.phenotypes)
+    }
+
+    /// Variant indices of the coded covariates, after the genotype columns
+    pub fn covariate_indices(&self) -> std::ops::Range<usize> {
+        let n_cols = self.n_genotypes as usize;
+        n_cols..n_cols + self.covariate_codes.len()
+    }
+
+    /// Add covariates as split candidates forced into every tree
+    /// Each covariate is coded like a genotype, 0 up to its median over the active subjects and 1 above,
+    /// missing values as MISSING_GENOTYPE
+    pub fn add_covariate_splits(&mut self, covariates: &Covariates) {
+        for k in 0..covariates.names.len() {
+            let active: Vec<f64> = self.sample_indices.iter()
+                .map(|s| covariates.values[*s][k])
+                .filter(|v| v.is_finite())
+                .collect();
+            let median = statistics::median(&active);
+            self.covariate_codes.push(covariates.values.iter().map(|row| match row[k] {
+                v if !v.is_finite() => MISSING_GENOTYPE,
+                v if v <= median => 0,
+                _ => 1
+            }).collect());
+        }
+    }
+
+    /// Regress the phenotype on the covariates (with an intercept) over the active subjects and grow the
+    /// trees on the residuals, the phenotypes are kept as they are
+    /// Rows with a missing covariate keep their phenotype, returns the coefficients (intercept first)
+    /// The covariates are kept so refit_residuals can fit again on other active subjects
+    pub fn residualize(&mut self, covariates: &Covariates) -> Result<Vec<f64>, String> {
+        self.adjustment = Some(Arc::new(covariates.clone()));
+        self.fit_residuals()
+    }
+
+    /// Fit the residualization again on the active subjects (e.g. the training subjects of a fold),
+    /// the residuals of all rows then come from this fit; nothing to do if the phenotype was not residualized
+    pub fn refit_residuals(&mut self) -> Result<(), String> {
+        match self.adjustment {
+            Some(_) => self.fit_residuals().map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    fn fit_residuals(&mut self) -> Result<Vec<f64>, String> {
+        let covariates = match &self.adjustment {
+            Some(c) => Arc::clone(c),
+            None => return Err(String::from("there are no covariates to residualize on"))
+        };
+        if self.outcome != Outcome::Continuous {
+            return Err(String::from("residualizing needs a continuous outcome"))
+        }
+        let design = |s: usize| -> Option<Vec<f64>> {
+            let row = &covariates.values[s];
+            if row.iter().all(|v| v.is_finite()) {
+                Some(std::iter::once(1.).chain(row.iter().copied()).collect())
+            } else {
+                None
+            }
+        };
+        let (x, y): (Vec<Vec<f64>>, Vec<f64>) = self.sample_indices.iter()
+            .filter_map(|s| design(*s).map(|row| (row, self.phenotypes[*s])))
+            .unzip();
+        if x.len() <= covariates.names.len() + 1 {
+            return Err(String::from("too few subjects with covariates to fit the regression"))
+        }
+        let coefficients = statistics::least_squares(&x, &y).ok_or("the covariates are collinear")?;
+        let residuals: Vec<f64> = (0..self.ids.len()).map(|s| match design(s) {
+            Some(row) => self.phenotypes[s] - row.iter().zip(coefficients.iter()).map(|(a, b)| a * b).sum::<f64>(),
+            None => self.phenotypes[s]
+        }).collect();
+        self.response = Some(residuals);
+        Ok(coefficients)
+    }
+
+    /// Stratify the phenotype-balanced sampling of a binary outcome by ancestry stratum (one label per row)
+    pub fn set_strata(&mut self, strata: Vec<usize>) -> Result<(), String> {
+        match (&self.balance, self.outcome) {
+            (Balance::Phenotype, Outcome::Binary) if strata.len() == self.ids.len() => {
+                self.strata = Some(strata);
+                Ok(())
+            },
+            (Balance::Phenotype, Outcome::Binary) => Err(String::from("there must be one stratum per sample")),
+            _ => Err(String::from("ancestry strata need a binary outcome with the default phenotype balancing"))
+        }
+    }
+}
+
+/// Draw n subjects from a group, with or without replacement
+fn draw(group: &[usize], n: usize, replace: bool, rng: &mut ThreadRng) -> Vec<usize> {
+    if !replace {
+        return group.choose_multiple(rng, n).copied().collect()
+    }
+    if group.is_empty() {
+        return Vec::new()
+    }
+    (0..n).map(|_| group[rng.gen_range(0..group.len())]).collect()
+}
+
+/// Draw subjects by inclusion probability
+/// Without replacement every subject is kept with its own probability,
+/// with replacement the expected number of subjects is drawn proportional to the probabilities
+fn draw_weighted(subjects: &[usize], probs: &[f64], replace: bool, rng: &mut ThreadRng) -> Vec<usize> {
+    if !replace {
+        return subjects.iter().zip(probs.iter())
+            .filter(|(_, p)| rng.gen_bool(p.min(1.)))
+            .map(|(s, _)| *s)
+            .collect()
+    }
+    let n_draw = probs.iter().sum::<f64>().round() as usize;
+    match WeightedIndex::new(probs) {
+        Ok(dist) => (0..n_draw).map(|_| subjects[dist.sample(rng)]).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/// Phenotype weight used for sampling and the number of outcome classes
+/// Panics if a categorical phenotype is not a valid class label
+fn summarize_outcome(phenotypes: &[f64], events: &[f64], outcome: &Outcome) -> (f64, usize) {
+    match outcome {
+        Outcome::Continuous => (-1., 0),
+        Outcome::Survival => {
+            if let Some(t) = phenotypes.iter().find(|t| **t < 0.) {
+                panic!("Survival times must not be negative, found {}", t);
+            }
+            if let Some(e) = events.iter().find(|e| **e != 0. && **e != 1.) {
+                panic!("Survival events must be coded 0 (censored) / 1 (event), found {}", e);
+            }
+            (-1., 0)
+        },
+        Outcome::Binary => {
+            // subjects coded otherwise (e.g. -9) are left out, see coded_samples
+            let coded: Vec<f64> = phenotypes.iter().filter(|p| **p == 0. || **p == 1.).copied().collect();
+            (coded.iter().sum::<f64>() / coded.len() as f64, 2)
+        },
+        Outcome::Categorical => {
+            if let Some(p) = phenotypes.iter().find(|p| **p < 0. || p.fract() != 0.) {
+                panic!("Categorical outcome must be coded as class labels 0..k-1, found {}", p);
+            }
+            (-1., phenotypes.iter().fold(0., |m: f64, p| m.max(*p)) as usize + 1)
+        }
+    }
+}
+
+/// Subjects the trees can grow on: a binary outcome keeps only the subjects coded 0/1
+fn coded_samples(phenotypes: &[f64], outcome: &Outcome) -> Vec<usize> {
+    if *outcome != Outcome::Binary {
+        return (0..phenotypes.len()).collect()
+    }
+    let coded: Vec<usize> = (0..phenotypes.len()).filter(|s| phenotypes[*s] == 0. || phenotypes[*s] == 1.).collect();
+    if coded.len() < phenotypes.len() {
+        eprintln!("Excluding {} subjects with a binary outcome other than 0/1", phenotypes.len() - coded.len());
+    }
+    coded
+}
+
+/// Time points for cumulative hazard estimates: the distinct event times,
+/// thinned to at most MAX_TIME_GRID evenly spaced quantiles
+fn make_time_grid(times: &[f64], events: &[f64]) -> Vec<f64> {
+    let mut event_times: Vec<f64> = times.iter().zip(events.iter()).filter(|(_, e)| **e == 1.).map(|(t, _)| *t).collect();
+    event_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    event_times.dedup();
+    if event_times.len() <= MAX_TIME_GRID {
+        return event_times
+    }
+    (0..MAX_TIME_GRID).map(|i| event_times[i * (event_times.len() - 1) / (MAX_TIME_GRID - 1)]).collect()
+}
+
+/// Parse a single genotype call, where NA, . or an empty field is missing
+fn parse_genotype(field: &str) -> u8 {
+    match field.trim() {
+        "" | "." | "NA" | "na" => MISSING_GENOTYPE,
+        x => match x.parse::<u8>() {
+            Ok(g) => g,
+            Err(e) => panic!("Unable to parse genotype {:?}: {}", x, e)
+        }
+    }
+}
+
+/// Build small matrices for unit tests from (phenotype, genotypes) rows, ids are s0, s1, ...
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static N_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn matrix(rows: &[(f64, Vec<u8>)], outcome: Outcome) -> GenoMatrix {
+        let path = std::env::temp_dir().join(format!("gf_test_{}_{}.csv", std::process::id(), N_FILES.fetch_add(1, Ordering::SeqCst)));
+        let lines: Vec<String> = rows.iter().enumerate().map(|(i, (p, g))| {
+            let calls: Vec<String> = g.iter().map(|c| if *c == MISSING_GENOTYPE { String::from("NA") } else { c.to_string() }).collect();
+            format!("s{},{},{}", i, p, calls.join(","))
+        }).collect();
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        let gm = crate::reader::read_matrix_csv(path.to_str().unwrap(), ",", &outcome, &false);
+        std::fs::remove_file(&path).unwrap();
+        gm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_outcome_excludes_uncoded_subjects() {
+        let rows: Vec<(f64, Vec<u8>)> = [0., 1., -9., 0., 1., 2.].iter().map(|p| (*p, vec![0, 1])).collect();
+        let gm = test_utils::matrix(&rows, Outcome::Binary);
+        assert_eq!(gm.sample_indices(), &vec![0, 1, 3, 4]);
+        assert_eq!(gm.pheno_weight, 0.5);
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            assert!(gm.sample_subjects(1., false, &mut rng).iter().all(|s| gm.phenotypes[*s] == 0. || gm.phenotypes[*s] == 1.));
+        }
     }
 }
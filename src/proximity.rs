@@ -0,0 +1,222 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Sample proximities from the forest
+//! The proximity of two subjects is the fraction of trees in which they end up in the same terminal node
+//! Used to find subgroups of subjects through MDS coordinates and hierarchical clustering
+
+use crate::matrix;
+use crate::statistics;
+use crate::tree;
+
+use rayon::prelude::*;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Sparse proximity matrix over the active subjects
+/// subjects = matrix row indices of the subjects, pairs are keyed by position in subjects (lower first)
+/// Pairs that never share a terminal node are left out, the diagonal is 1
+pub struct Proximity {
+    pub subjects: Vec<usize>,
+    pub pairs: HashMap<(usize, usize), f64>
+}
+
+/// Count how often each pair of active subjects shares a terminal node
+/// With oob_only a tree only counts for pairs that are both out of its bag, and the count is divided by
+/// the number of such trees; otherwise every tree counts for all active subjects
+pub fn compute(gm: &matrix::GenoMatrix, trees: &[tree::Node], bags: &[Vec<usize>], oob_only: bool) -> Proximity {
+    let subjects: Vec<usize> = gm.sample_indices().to_vec();
+    let n_rows = gm.ids.len();
+    let in_bag: Vec<Vec<bool>> = bags.par_iter().map(|bag| {
+        let mut b = vec![false; n_rows];
+        for s in bag {
+            b[*s] = true;
+        }
+        b
+    }).collect();
+    let counts: HashMap<(usize, usize), f64> = trees.par_iter().zip(in_bag.par_iter())
+        .filter(|(t, _)| !t.value.is_empty())
+        .fold(HashMap::new, |mut counts, (t, b)| {
+            let mut terminals: HashMap<*const tree::Node, Vec<usize>> = HashMap::new();
+            for (i, s) in subjects.iter().enumerate() {
+                if oob_only && b[*s] {
+                    continue
+                }
+                terminals.entry(t.terminal(gm, *s) as *const tree::Node).or_default().push(i);
+            }
+            for group in terminals.values() {
+                for (x, i) in group.iter().enumerate() {
+                    for j in &group[x + 1..] {
+                        *counts.entry((*i, *j)).or_insert(0.) += 1.;
+                    }
+                }
+            }
+            counts
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (pair, c) in b {
+                *a.entry(pair).or_insert(0.) += c;
+            }
+            a
+        });
+    let n_trees = trees.iter().filter(|t| !t.value.is_empty()).count() as f64;
+    // bit t of oob_trees[i] is set if subject i is out of the bag of tree t
+    let oob_trees: Vec<Vec<u64>> = match oob_only {
+        true => subjects.par_iter().map(|s| {
+            let mut bits = vec![0u64; trees.len().div_ceil(64)];
+            for (t, (tree, b)) in trees.iter().zip(in_bag.iter()).enumerate() {
+                if !tree.value.is_empty() && !b[*s] {
+                    bits[t / 64] |= 1 << (t % 64);
+                }
+            }
+            bits
+        }).collect(),
+        false => Vec::new()
+    };
+    let pairs: HashMap<(usize, usize), f64> = counts.into_par_iter().map(|((i, j), c)| {
+        let denominator = match oob_only {
+            true => oob_trees[i].iter().zip(oob_trees[j].iter()).map(|(a, b)| (a & b).count_ones()).sum::<u32>() as f64,
+            false => n_trees
+        };
+        ((i, j), c / denominator)
+    }).collect();
+    Proximity { subjects, pairs }
+}
+
+impl Proximity {
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        if i == j {
+            return 1.
+        }
+        *self.pairs.get(&(i.min(j), i.max(j))).unwrap_or(&0.)
+    }
+
+    /// Write the non-zero proximities as id, id, proximity rows
+    pub fn write(&self, path: &str, gm: &matrix::GenoMatrix) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "id_1\tid_2\tproximity")?;
+        let mut pairs: Vec<(&(usize, usize), &f64)> = self.pairs.iter().collect();
+        pairs.sort_by_key(|(pair, _)| **pair);
+        for ((i, j), p) in pairs {
+            writeln!(w, "{}\t{}\t{}", gm.ids[self.subjects[*i]], gm.ids[self.subjects[*j]], p)?;
+        }
+        w.flush()
+    }
+
+    /// Classical multidimensional scaling of the distances 1 - proximity
+    /// Returns n_dims coordinates per subject, the leading eigenvectors of the double-centred
+    /// squared distances are found by power iteration with deflation
+    pub fn mds(&self, n_dims: usize) -> Vec<Vec<f64>> {
+        let n = self.subjects.len();
+        // squared distance is (1 - proximity)^2, double centring gives b_ij = -(d2_ij - row_i - row_j + total) / 2
+        let mut b: Vec<Vec<f64>> = (0..n).into_par_iter().map(|i| (0..n).map(|j| (1. - self.get(i, j)).powi(2)).collect()).collect();
+        let row_means: Vec<f64> = b.iter().map(|r| r.iter().sum::<f64>() / n as f64).collect();
+        let total_mean: f64 = row_means.iter().sum::<f64>() / n as f64;
+        b.par_iter_mut().enumerate().for_each(|(i, row)| {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = -0.5 * (*x - row_means[i] - row_means[j] + total_mean);
+            }
+        });
+        let mut coords: Vec<Vec<f64>> = vec![Vec::with_capacity(n_dims); n];
+        for _ in 0..n_dims {
+            let (lambda, v) = statistics::leading_eigen(&b);
+            let scale = lambda.max(0.).sqrt();
+            for i in 0..n {
+                coords[i].push(v[i] * scale);
+            }
+            // deflate so the next power iteration finds the following eigenvector
+            b.par_iter_mut().enumerate().for_each(|(i, row)| {
+                for (j, x) in row.iter_mut().enumerate() {
+                    *x -= lambda * v[i] * v[j];
+                }
+            });
+        }
+        coords
+    }
+
+    /// Average-linkage hierarchical clustering of the distances 1 - proximity, cut into n_clusters
+    /// Uses the nearest-neighbour chain algorithm, the merges are then replayed in order of height
+    /// Returns a cluster label (1..n_clusters, largest cluster first) per subject
+    pub fn cluster(&self, n_clusters: usize) -> Vec<usize> {
+        let n = self.subjects.len();
+        if n == 0 {
+            return Vec::new()
+        }
+        let mut d: Vec<Vec<f64>> = (0..n).into_par_iter().map(|i| (0..n).map(|j| 1. - self.get(i, j)).collect()).collect();
+        let mut size: Vec<f64> = vec![1.; n];
+        let mut active: Vec<bool> = vec![true; n];
+        let mut merges: Vec<(f64, usize, usize)> = Vec::with_capacity(n);
+        let mut chain: Vec<usize> = Vec::new();
+        while merges.len() + 1 < n {
+            if chain.is_empty() {
+                chain.push((0..n).find(|i| active[*i]).unwrap());
+            }
+            let a = *chain.last().unwrap();
+            let prev = if chain.len() > 1 { Some(chain[chain.len() - 2]) } else { None };
+            // nearest active neighbour of a, preferring the previous chain element on ties
+            let mut nearest = prev.unwrap_or(usize::MAX);
+            let mut nearest_d = prev.map_or(f64::INFINITY, |p| d[a][p]);
+            for k in 0..n {
+                if active[k] && k != a && d[a][k] < nearest_d {
+                    nearest = k;
+                    nearest_d = d[a][k];
+                }
+            }
+            if Some(nearest) != prev {
+                chain.push(nearest);
+                continue
+            }
+            chain.pop();
+            chain.pop();
+            let b = nearest;
+            merges.push((nearest_d, a, b));
+            // Lance-Williams update for average linkage, the merged cluster takes the place of a
+            for k in 0..n {
+                if active[k] && k != a && k != b {
+                    let dk = (size[a] * d[a][k] + size[b] * d[b][k]) / (size[a] + size[b]);
+                    d[a][k] = dk;
+                    d[k][a] = dk;
+                }
+            }
+            size[a] += size[b];
+            active[b] = false;
+        }
+        merges.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
+        let mut parent: Vec<usize> = (0..n).collect();
+        for (_, a, b) in merges.iter().take(n.saturating_sub(n_clusters.max(1))) {
+            let (ra, rb) = (find(&mut parent, *a), find(&mut parent, *b));
+            parent[rb] = ra;
+        }
+        let roots: Vec<usize> = (0..n).map(|i| find(&mut parent, i)).collect();
+        let mut root_sizes: HashMap<usize, usize> = HashMap::new();
+        for r in &roots {
+            *root_sizes.entry(*r).or_insert(0) += 1;
+        }
+        let mut ordered: Vec<(usize, usize)> = root_sizes.into_iter().collect();
+        ordered.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+        let labels: HashMap<usize, usize> = ordered.iter().enumerate().map(|(l, (r, _))| (*r, l + 1)).collect();
+        roots.iter().map(|r| labels[r]).collect()
+    }
+}
+
+/// Root of an element in the union-find forest, with path halving
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Write a value table with one row per subject
+pub fn write_subject_table(path: &str, gm: &matrix::GenoMatrix, subjects: &[usize], header: &str, rows: &[String]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "id\t{}", header)?;
+    for (s, row) in subjects.iter().zip(rows.iter()) {
+        writeln!(w, "{}\t{}", gm.ids[*s], row)?;
+    }
+    w.flush()
+}
@@ -0,0 +1,106 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Progressive pruning of the iterative forest
+//! After every iteration the weakest active variants are dropped and the next forest grows on the survivors,
+//! variants a forest never split on count as importance 0
+
+use crate::variants;
+
+use std::collections::{HashMap, HashSet};
+
+/// Which variants to drop after each iteration
+/// Fraction = the given fraction of the active variants with the lowest importance (at least one)
+/// ZScore = variants whose importance z score among the active variants is below the cut
+#[derive(Clone, Copy, Debug)]
+pub enum Rule {
+    Fraction(f64),
+    ZScore(f64)
+}
+
+/// Pruning schedule: the drop rule and when to stop
+/// target stops the iterations once no more than this many variants are active (never pruning below it),
+/// stop_on_oob stops them once the OOB error is higher than in the previous iteration
+#[derive(Clone, Copy)]
+pub struct Schedule {
+    pub rule: Option<Rule>,
+    pub target: Option<usize>,
+    pub stop_on_oob: bool
+}
+
+/// Round at which each variant of the iterative phase was dropped
+pub struct Eliminations {
+    variants: Vec<usize>,
+    round: HashMap<usize, usize>
+}
+
+impl Schedule {
+
+    /// Whether the active variants have reached the target count
+    pub fn reached_target(&self, n_active: usize) -> bool {
+        self.target.is_some_and(|t| n_active <= t)
+    }
+
+    /// The active variants that survive this round, in their original order, never fewer than one
+    pub fn survivors(&self, importances: &HashMap<usize, f64>, active: &[usize]) -> Vec<usize> {
+        let rule = match self.rule {
+            Some(r) => r,
+            None => return active.to_vec()
+        };
+        let imp = |v: &usize| *importances.get(v).unwrap_or(&0.);
+        let mut ranked: Vec<usize> = active.to_vec();
+        ranked.sort_by(|a, b| imp(a).partial_cmp(&imp(b)).unwrap_or(std::cmp::Ordering::Equal).then(a.cmp(b)));
+        let mut n_drop = match rule {
+            Rule::Fraction(f) => ((active.len() as f64 * f).floor() as usize).max(1),
+            Rule::ZScore(z) => {
+                let values: Vec<f64> = active.iter().map(imp).collect();
+                let n = values.len() as f64;
+                let mean = values.iter().sum::<f64>() / n;
+                let sd = (values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / n).sqrt();
+                if sd > 0. { values.iter().filter(|x| (*x - mean) / sd < z).count() } else { 0 }
+            }
+        };
+        if let Some(t) = self.target {
+            n_drop = n_drop.min(active.len().saturating_sub(t));
+        }
+        // the next forest needs at least one variant to grow on
+        n_drop = n_drop.min(active.len().saturating_sub(1));
+        let dropped: HashSet<usize> = ranked[..n_drop].iter().copied().collect();
+        active.iter().filter(|v| !dropped.contains(v)).copied().collect()
+    }
+}
+
+impl Eliminations {
+
+    /// Start tracking the variants entering the iterative phase
+    pub fn new(variants: &[usize]) -> Self {
+        Eliminations { variants: variants.to_vec(), round: HashMap::new() }
+    }
+
+    /// Record the variants of active missing from survivors as dropped at this round
+    pub fn record(&mut self, round: usize, active: &[usize], survivors: &[usize]) {
+        let kept: HashSet<&usize> = survivors.iter().collect();
+        for v in active {
+            if !kept.contains(v) {
+                self.round.insert(*v, round);
+            }
+        }
+    }
+
+    /// Undo the drops recorded at this round, the variants are active again
+    pub fn restore(&mut self, round: usize) {
+        self.round.retain(|_, r| *r != round);
+    }
+
+    /// print the round each variant was dropped at (NA if it survived) to stdout
+    pub fn print(&self, variants: &[variants::Variant]) {
+        println!("#ELIMINATION");
+        println!("#id\teliminated_round");
+        for v in &self.variants {
+            match self.round.get(v) {
+                Some(r) => println!("{}\t{}", variants[*v].id, r),
+                None => println!("{}\tNA", variants[*v].id)
+            }
+        }
+    }
+}
@@ -7,33 +7,87 @@ use crate::matrix;
 use crate::variants;
 use crate::utils;
 
+use memmap2::Mmap;
+
 use std::str;
 use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+/// Magic bytes at the start of a binary genotype cache
+const CACHE_MAGIC: &[u8; 8] = b"GFCACHE3";
 
-pub fn read_matrix_csv(path: &str, sep: &str, continuous_outcome: &bool) -> matrix::GenoMatrix {
+/// Read a csv/tsv genotype matrix
+/// When has_header is set the first row holds column names and is skipped (see read_matrix_header)
+/// Other lines starting with # are comments
+pub fn read_matrix_csv(path: &str, sep: &str, outcome: &matrix::Outcome, has_header: &bool) -> matrix::GenoMatrix {
     let mut beginning: csv::Position = csv::Position::new();
     beginning.set_line(1);
-    let mut reader = match make_reader(path, sep) {
+    let mut reader = match make_reader(path, sep, true) {
         Ok(reader) => reader,
         Err(why) => panic!("Error with making the reader: {:?}", why)
     };
-    let shape = utils::get_mat_size(&mut reader);
+    let mut shape = utils::get_mat_size(&mut reader, outcome.leading_columns());
     match reader.seek(beginning) {
         Ok(_) => (),
         Err(e) => panic!("Error in file seek {:?}", e)
     }
-    return matrix::GenoMatrix::new(&mut reader, shape, continuous_outcome);
+    // A header starting with # is already skipped as a comment
+    if *has_header && !first_line_is_comment(path) {
+        shape.0 -= 1;
+        if let Some(Err(e)) = reader.records().next() {
+            panic!("Error reading the header row {:?}", e)
+        }
+    }
+    matrix::GenoMatrix::new(&mut reader, shape, outcome)
+}
+
+/// Variant IDs from the header row of a genotype matrix (every column after id and the outcome columns)
+/// The header is the first line, even if it starts with #
+pub fn read_matrix_header(path: &str, sep: &str, outcome: &matrix::Outcome) -> Result<Vec<String>, String> {
+    let mut reader = make_reader(path, sep, false).map_err(|e| format!("{}: {}", path, e))?;
+    match reader.records().next() {
+        Some(Ok(record)) => Ok(record.iter().skip(outcome.leading_columns()).map(String::from).collect()),
+        Some(Err(e)) => Err(format!("{}: {}", path, e)),
+        None => Err(format!("{} is empty", path))
+    }
 }
 
-pub fn read_variant_table(path: &str, sep: &str, n_genotypes: &f64) -> Vec<variants::Variant> {
+/// Build the variant table for a matrix with n_genotypes columns
+/// From the variant file if one was given (it must exist), otherwise from the matrix header IDs,
+/// otherwise numeric dummy IDs. When both a variant file and header IDs are available they must agree.
+pub fn read_variant_table(path: &Option<String>, sep: &str, n_genotypes: &f64, header_ids: Option<Vec<String>>) -> Result<Vec<variants::Variant>, String> {
     let mut variants: Vec<variants::Variant> = Vec::new();
-    match make_reader(path, sep) {
-        Ok(mut reader) => build_variant_array(&mut variants, &mut reader),
-        Err(_) => build_dummy_variant_array(&mut variants, n_genotypes)
+    match (path, &header_ids) {
+        (Some(p), _) => {
+            let mut reader = make_reader(p, sep, true).map_err(|e| format!("{}: {}", p, e))?;
+            build_variant_array(&mut variants, &mut reader).map_err(|e| format!("{}: {}", p, e))?;
+        },
+        (None, Some(ids)) => variants.extend(ids.iter().map(|id| variants::Variant::new(id.to_string()))),
+        (None, None) => build_dummy_variant_array(&mut variants, n_genotypes)
     };
-    variants
+    validate_variants(&variants, n_genotypes)?;
+    if let (Some(p), Some(ids)) = (path, &header_ids) {
+        if let Some((i, (v, id))) = variants.iter().zip(ids.iter()).enumerate().find(|(_, (v, id))| v.id != **id) {
+            return Err(format!("{} row {} has variant {} but the matrix header has {}", p, i + 1, v.id, id))
+        }
+    }
+    Ok(variants)
 }
 
+/// Check that there is exactly one variant per genotype column and that IDs are unique
+fn validate_variants(variants: &[variants::Variant], n_genotypes: &f64) -> Result<(), String> {
+    if variants.len() != *n_genotypes as usize {
+        return Err(format!("variant table has {} variants but the genotype matrix has {} genotype columns", variants.len(), n_genotypes))
+    }
+    let mut seen: HashSet<&str> = HashSet::new();
+    let duplicates: Vec<&str> = variants.iter().map(|v| v.id.as_str()).filter(|id| !seen.insert(id)).collect();
+    if !duplicates.is_empty() {
+        let shown: Vec<&str> = duplicates.iter().take(5).copied().collect();
+        return Err(format!("variant table has {} duplicated IDs (e.g. {})", duplicates.len(), shown.join(", ")))
+    }
+    Ok(())
+}
 
 fn build_dummy_variant_array(variants: &mut Vec<variants::Variant>, n_genotypes: &f64) {
     // When a variant id file is not provided
@@ -42,20 +96,360 @@ fn build_dummy_variant_array(variants: &mut Vec<variants::Variant>, n_genotypes:
     }
 }
     
-fn build_variant_array(variants: &mut Vec<variants::Variant>, reader: &mut csv::Reader<File>) {
-    for result in reader.records() {
-        let record = result.unwrap();
-        variants.push(variants::Variant::new(String::from(&record[1])));
+fn build_variant_array(variants: &mut Vec<variants::Variant>, reader: &mut csv::Reader<File>) -> Result<(), String> {
+    for (i, result) in reader.records().enumerate() {
+        let record = result.map_err(|e| e.to_string())?;
+        if record.len() < 2 {
+            return Err(format!("row {} has fewer than 2 columns (index, id)", i + 1))
+        }
+        let fields: Vec<&str> = record.iter().skip(1).collect();
+        variants.push(variants::Variant::from_fields(&fields));
+    }
+    Ok(())
+}
+
+/// Write a binary genotype cache so later runs can skip parsing the text matrix
+/// Layout (little endian): magic, n_subjects (u64), n_genotypes (u64), sample ids,
+/// phenotypes (f64), event count (u64, 0 unless survival) + events (f64), variant count (u64) + variant rows,
+/// then genotypes packed 2 bits each, variant-major.
+/// Strings are stored as a u32 length followed by the bytes, variant rows as a u32 field count followed by the fields.
+pub fn write_matrix_cache(path: &str, gm: &matrix::GenoMatrix, variants: &[variants::Variant]) -> io::Result<()> {
+    let n_subjects = gm.n_subjects as usize;
+    let n_genotypes = gm.n_genotypes as usize;
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(CACHE_MAGIC)?;
+    w.write_all(&(n_subjects as u64).to_le_bytes())?;
+    w.write_all(&(n_genotypes as u64).to_le_bytes())?;
+    for id in &gm.ids {
+        write_cache_string(&mut w, id)?;
+    }
+    for p in &gm.phenotypes {
+        w.write_all(&p.to_le_bytes())?;
+    }
+    w.write_all(&(gm.events.len() as u64).to_le_bytes())?;
+    for e in &gm.events {
+        w.write_all(&e.to_le_bytes())?;
+    }
+    w.write_all(&(variants.len() as u64).to_le_bytes())?;
+    for v in variants {
+        let fields = v.fields();
+        w.write_all(&(fields.len() as u32).to_le_bytes())?;
+        for f in &fields {
+            write_cache_string(&mut w, f)?;
+        }
+    }
+    let mut col: Vec<u8> = vec![0; matrix::packed_col_bytes(n_subjects)];
+    for g in 0..n_genotypes {
+        col.iter_mut().for_each(|b| *b = 0);
+        for s in 0..n_subjects {
+            let code = *gm.genotype(s, g);
+            if code > 3 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("genotype {} (subject {}, variant {}) cannot be packed into the cache", code, s, g)))
+            }
+            col[s / 4] |= code << ((s % 4) * 2);
+        }
+        w.write_all(&col)?;
+    }
+    w.flush()
+}
+
+/// Memory-map a binary genotype cache written by write_matrix_cache
+pub fn read_matrix_cache(path: &str, outcome: &matrix::Outcome) -> Result<matrix::GenoMatrix, String> {
+    let mmap = map_cache(path).map_err(|e| format!("{}: {}", path, e))?;
+    let header = read_cache_header(&mmap).map_err(|e| format!("{}: {}", path, e))?;
+    if (*outcome == matrix::Outcome::Survival) == header.events.is_empty() {
+        return Err(format!("{} does not match the outcome type ({:?})", path, outcome))
+    }
+    let n_subjects = header.ids.len();
+    let packed_end = header.n_genotypes.checked_mul(matrix::packed_col_bytes(n_subjects)).and_then(|n| n.checked_add(header.offset));
+    if packed_end.is_none_or(|end| mmap.len() < end) {
+        return Err(format!("{} is truncated", path))
+    }
+    let packed = matrix::PackedGenotypes::new(mmap, header.offset, n_subjects);
+    Ok(matrix::GenoMatrix::from_packed(header.ids, header.phenotypes, header.events, header.n_genotypes, packed, outcome))
+}
+
+/// Read the variant table stored in a binary genotype cache
+pub fn read_cache_variants(path: &str) -> Result<Vec<variants::Variant>, String> {
+    let mmap = map_cache(path).map_err(|e| format!("{}: {}", path, e))?;
+    let header = read_cache_header(&mmap).map_err(|e| format!("{}: {}", path, e))?;
+    let n_genotypes = header.n_genotypes as f64;
+    let mut variants: Vec<variants::Variant> = header.variants.iter()
+        .map(|fields| variants::Variant::from_fields(&fields.iter().map(|f| f.as_str()).collect::<Vec<&str>>()))
+        .collect();
+    if variants.is_empty() {
+        build_dummy_variant_array(&mut variants, &n_genotypes);
+    }
+    validate_variants(&variants, &n_genotypes).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(variants)
+}
+
+/// Everything in a cache before the packed genotypes, offset is where they start
+struct CacheHeader {
+    n_genotypes: usize,
+    ids: Vec<String>,
+    phenotypes: Vec<f64>,
+    events: Vec<f64>,
+    variants: Vec<Vec<String>>,
+    offset: usize
+}
+
+fn read_cache_header(buf: &[u8]) -> Result<CacheHeader, String> {
+    let mut cur = CacheCursor::new(buf);
+    let n_subjects = cur.read_u64()? as usize;
+    let n_genotypes = cur.read_u64()? as usize;
+    let ids = (0..n_subjects).map(|_| cur.read_string()).collect::<Result<Vec<String>, String>>()?;
+    let phenotypes = (0..n_subjects).map(|_| cur.read_f64()).collect::<Result<Vec<f64>, String>>()?;
+    let n_events = cur.read_u64()? as usize;
+    let events = (0..n_events).map(|_| cur.read_f64()).collect::<Result<Vec<f64>, String>>()?;
+    let n_variants = cur.read_u64()? as usize;
+    let variants = (0..n_variants).map(|_| cur.read_fields()).collect::<Result<Vec<Vec<String>>, String>>()?;
+    Ok(CacheHeader { n_genotypes, ids, phenotypes, events, variants, offset: cur.pos })
+}
+
+fn map_cache(path: &str) -> io::Result<Mmap> {
+    let f = File::open(path)?;
+    // The cache is treated as read-only for the lifetime of the run
+    let mmap = unsafe { Mmap::map(&f)? };
+    if mmap.len() < CACHE_MAGIC.len() || &mmap[..CACHE_MAGIC.len()] != CACHE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a genetic forest genotype cache"))
+    }
+    Ok(mmap)
+}
+
+fn write_cache_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+/// Sequential reader over the header of a mapped cache
+struct CacheCursor<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> CacheCursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        CacheCursor { buf, pos: CACHE_MAGIC.len() }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err(String::from("the cache is truncated"))
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn read_fields(&mut self) -> Result<Vec<String>, String> {
+        let n = u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize;
+        (0..n).map(|_| self.read_string()).collect()
+    }
+}
+
+/// Write per-subject predictions as a tab separated table
+/// Continuous outcomes get the predicted value, binary and categorical outcomes the predicted class
+/// followed by the probability of each class, survival outcomes the ensemble mortality (summed cumulative hazard)
+/// followed by the cumulative hazard at each time point
+pub fn write_predictions(path: &str, gm: &matrix::GenoMatrix, subjects: &[usize], predictions: &[Vec<f64>]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let (observed, extra_cols): (&str, Vec<String>) = match gm.outcome {
+        matrix::Outcome::Survival => ("time\tevent", gm.time_grid.iter().map(|t| format!("\tH_{}", t)).collect()),
+        _ => ("observed", (0..gm.n_classes).map(|c| format!("\tp_{}", c)).collect())
+    };
+    writeln!(w, "id\t{}\tpredicted{}", observed, extra_cols.concat())?;
+    for (s, pred) in subjects.iter().zip(predictions.iter()) {
+        let predicted = match (gm.outcome, pred.is_empty()) {
+            (_, true) => String::from("NA"),
+            (matrix::Outcome::Continuous, _) => pred[0].to_string(),
+            (matrix::Outcome::Survival, _) => pred.iter().sum::<f64>().to_string(),
+            _ => utils::argmax(pred).to_string()
+        };
+        let extra: Vec<String> = match gm.outcome {
+            matrix::Outcome::Continuous => Vec::new(),
+            _ if pred.is_empty() => vec![String::from("\tNA"); extra_cols.len()],
+            _ => pred.iter().map(|p| format!("\t{}", p)).collect()
+        };
+        let observed = match gm.outcome {
+            matrix::Outcome::Survival => format!("{}\t{}", gm.phenotypes[*s], gm.events[*s]),
+            _ => gm.response()[*s].to_string()
+        };
+        writeln!(w, "{}\t{}\t{}{}", gm.ids[*s], observed, predicted, extra.concat())?;
+    }
+    w.flush()
+}
+
+/// Read per-sample weights (sample ID and weight, whitespace delimited) in the order of the matrix IDs
+/// Every sample in the matrix must have a weight
+pub fn read_sample_weights(path: &str, ids: &[String]) -> Result<Vec<f64>, String> {
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue
+        }
+        let weight = fields.get(1)
+            .ok_or(format!("{}: no weight for sample {}", path, fields[0]))?
+            .parse::<f64>()
+            .map_err(|e| format!("{}: weight for sample {}: {}", path, fields[0], e))?;
+        weights.insert(String::from(fields[0]), weight);
+    }
+    let missing: Vec<&str> = ids.iter().filter(|id| !weights.contains_key(*id)).map(|id| id.as_str()).collect();
+    if !missing.is_empty() {
+        return Err(format!("{}: {} samples have no weight (e.g. {})", path, missing.len(), missing[0]))
     }
+    Ok(ids.iter().map(|id| weights[id]).collect())
 }
 
-fn make_reader(path: &str, sep: &str) -> Result<csv::Reader<File>, &'static str> {
+/// Read a covariate table: a header row of id and covariate names, then one row per sample
+/// Whitespace delimited, lines starting with # are skipped; NA or . is a missing value
+/// Rows follow the matrix samples (ids), samples missing from the file get missing values
+pub fn read_covariates(path: &str, ids: &[String]) -> Result<matrix::Covariates, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut names: Option<Vec<String>> = None;
+    let mut rows: HashMap<String, Vec<f64>> = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue
+        }
+        let n_covariates = match &names {
+            Some(n) => n.len(),
+            None => {
+                names = Some(fields[1..].iter().map(|f| String::from(*f)).collect());
+                continue
+            }
+        };
+        if fields.len() != n_covariates + 1 {
+            return Err(format!("{}: sample {} has {} values, expected {}", path, fields[0], fields.len() - 1, n_covariates))
+        }
+        let values = fields[1..].iter().map(|f| match *f {
+            "NA" | "." => Ok(f64::NAN),
+            _ => f.parse::<f64>().map_err(|e| format!("{}: sample {}: {}", path, fields[0], e))
+        }).collect::<Result<Vec<f64>, String>>()?;
+        rows.insert(String::from(fields[0]), values);
+    }
+    let names = names.ok_or(format!("{}: no header row", path))?;
+    let values = ids.iter().map(|id| rows.remove(id).unwrap_or(vec![f64::NAN; names.len()])).collect();
+    Ok(matrix::Covariates { names, values })
+}
+
+/// Read a list of sample or variant IDs, one per line (first whitespace-delimited column)
+/// Blank lines and lines starting with # are skipped
+pub fn read_id_list(path: &str) -> io::Result<HashSet<String>> {
+    let mut ids: HashSet<String> = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Some(id) = line.split_whitespace().next() {
+            if !id.starts_with('#') {
+                ids.insert(String::from(id));
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Read the regions of a BED file
+/// BED starts are 0-based and ends exclusive, so they are shifted to 1-based inclusive regions
+pub fn read_bed(path: &str) -> io::Result<Vec<variants::Region>> {
+    let mut regions: Vec<variants::Region> = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') || fields[0] == "track" || fields[0] == "browser" {
+            continue
+        }
+        if fields.len() < 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("BED line has fewer than 3 columns: {}", line)))
+        }
+        let parse = |x: &str| x.parse::<u64>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", line, e)));
+        regions.push(variants::Region {
+            chrom: variants::normalize_chrom(fields[0]),
+            start: parse(fields[1])? + 1,
+            end: parse(fields[2])?
+        });
+    }
+    Ok(regions)
+}
+
+fn make_reader(path: &str, sep: &str, comments: bool) -> Result<csv::Reader<File>, &'static str> {
     match File::open(path) {
         Ok(f) => Ok(csv::ReaderBuilder::new()
             .has_headers(false)
             .delimiter(sep.as_bytes()[0])
-            .comment(Some(b'#'))
+            .comment(if comments { Some(b'#') } else { None })
             .from_reader(f)),
         Err(_) => Err("Unable to load file"),
     }
 }
+
+fn first_line_is_comment(path: &str) -> bool {
+    let mut line = String::new();
+    match File::open(path).map(|f| BufReader::new(f).read_line(&mut line)) {
+        Ok(Ok(_)) => line.starts_with('#'),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    #[test]
+    fn cache_round_trip_and_truncation() {
+        let rows: Vec<(f64, Vec<u8>)> = (0..9).map(|i| ((i % 2) as f64, vec![(i % 3 == 0) as u8, 1, matrix::MISSING_GENOTYPE])).collect();
+        let gm = test_utils::matrix(&rows, matrix::Outcome::Binary);
+        let variants: Vec<variants::Variant> = (0..3).map(|i| variants::Variant::new(format!("v{}", i))).collect();
+        let path = std::env::temp_dir().join(format!("gf_test_cache_{}.gfc", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_matrix_cache(path, &gm, &variants).unwrap();
+        let cached = read_matrix_cache(path, &matrix::Outcome::Binary).unwrap();
+        assert_eq!(cached.ids, gm.ids);
+        for s in 0..9 {
+            for g in 0..3 {
+                assert_eq!(cached.genotype(s, g), gm.genotype(s, g));
+            }
+        }
+        assert_eq!(read_cache_variants(path).unwrap().iter().map(|v| v.id.clone()).collect::<Vec<String>>(), vec!["v0", "v1", "v2"]);
+        assert!(read_matrix_cache(path, &matrix::Outcome::Survival).is_err());
+        let bytes = std::fs::read(path).unwrap();
+        for len in [bytes.len() - 1, bytes.len() / 2, CACHE_MAGIC.len() + 3] {
+            std::fs::write(path, &bytes[..len]).unwrap();
+            assert!(read_matrix_cache(path, &matrix::Outcome::Binary).is_err());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn header_starting_with_comment_character() {
+        let path = std::env::temp_dir().join(format!("gf_test_header_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        for header in ["#id,pheno,v0,v1", "id,pheno,v0,v1"] {
+            std::fs::write(path, format!("{}\ns0,1,1,0\n# a comment\ns1,0,0,1\n", header)).unwrap();
+            assert_eq!(read_matrix_header(path, ",", &matrix::Outcome::Binary).unwrap(), vec!["v0", "v1"]);
+            let gm = read_matrix_csv(path, ",", &matrix::Outcome::Binary, &true);
+            assert_eq!(gm.ids, vec!["s0", "s1"]);
+            assert_eq!((*gm.genotype(0, 0), *gm.genotype(1, 1)), (1, 1));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Stability of the importance ranking between successive forests of the iterative run
+//! Each forest is compared with the one before it over the active variants, variants a forest
+//! never split on count as importance 0
+
+use crate::statistics;
+use crate::variants;
+
+use std::collections::{HashMap, HashSet};
+
+/// Comparison of a forest with the previous one
+/// ranks holds (variant, rank, previous rank) for the active variants, rank 1 being the most important
+pub struct Stability {
+    pub spearman: f64,
+    pub jaccard: f64,
+    pub ranks: Vec<(usize, usize, usize)>
+}
+
+/// Thresholds for stopping the iterations once the ranking has converged
+/// The ranking has converged when every given threshold is met for `rounds` iterations in a row
+#[derive(Clone, Copy)]
+pub struct Convergence {
+    pub min_spearman: Option<f64>,
+    pub min_jaccard: Option<f64>,
+    pub rounds: usize
+}
+
+/// Keeps the previous importances and counts the iterations in a row that met the thresholds
+pub struct Tracker {
+    pub top: usize,
+    convergence: Option<Convergence>,
+    previous: Option<HashMap<usize, f64>>,
+    stable_rounds: usize
+}
+
+impl Tracker {
+
+    pub fn new(top: usize, convergence: Option<Convergence>) -> Self {
+        Tracker {
+            top,
+            convergence,
+            previous: None,
+            stable_rounds: 0
+        }
+    }
+
+    /// Compare the importances with the previous forest over the active variants and remember them
+    /// Returns None for the first forest
+    pub fn update(&mut self, importances: &HashMap<usize, f64>, active: &[usize]) -> Option<Stability> {
+        let result = self.previous.as_ref().map(|previous| compare(previous, importances, active, self.top));
+        if let (Some(s), Some(c)) = (&result, &self.convergence) {
+            let met = c.min_spearman.is_none_or(|m| s.spearman >= m) && c.min_jaccard.is_none_or(|m| s.jaccard >= m);
+            self.stable_rounds = if met { self.stable_rounds + 1 } else { 0 };
+        }
+        self.previous = Some(importances.clone());
+        result
+    }
+
+    /// Whether the ranking has met the convergence thresholds for enough iterations in a row
+    pub fn converged(&self) -> bool {
+        match &self.convergence {
+            Some(c) => self.stable_rounds >= c.rounds.max(1),
+            None => false
+        }
+    }
+}
+
+/// Spearman correlation of the importances, Jaccard overlap of the top variants and the rank of
+/// every active variant in both forests
+/// The correlation is NaN when either forest has all importances tied
+pub fn compare(previous: &HashMap<usize, f64>, current: &HashMap<usize, f64>, active: &[usize], top: usize) -> Stability {
+    let imps = |m: &HashMap<usize, f64>| -> HashMap<usize, f64> { active.iter().map(|v| (*v, *m.get(v).unwrap_or(&0.))).collect() };
+    let (prev, curr) = (imps(previous), imps(current));
+    let values = |m: &HashMap<usize, f64>| -> Vec<f64> { active.iter().map(|v| m[v]).collect() };
+    let (prev_values, curr_values) = (values(&prev), values(&curr));
+    let tied = |x: &[f64]| x.iter().all(|v| *v == x[0]);
+    let spearman = if active.len() < 2 || tied(&prev_values) || tied(&curr_values) {
+        f64::NAN
+    } else {
+        statistics::correlation(&statistics::ranks(&prev_values), &statistics::ranks(&curr_values))
+    };
+    let (prev_top, curr_top) = (top_variants(&prev, top), top_variants(&curr, top));
+    let union = prev_top.union(&curr_top).count();
+    let jaccard = if union > 0 { prev_top.intersection(&curr_top).count() as f64 / union as f64 } else { f64::NAN };
+    let prev_rank = ranking(&prev);
+    let ranks: Vec<(usize, usize, usize)> = ranking_order(&curr).into_iter().enumerate()
+        .map(|(i, v)| (v, i + 1, prev_rank[&v]))
+        .collect();
+    Stability { spearman, jaccard, ranks }
+}
+
+/// Variants ordered by decreasing importance, ties broken by variant index
+fn ranking_order(importances: &HashMap<usize, f64>) -> Vec<usize> {
+    let mut order: Vec<(&usize, &f64)> = importances.iter().collect();
+    order.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(b.0)));
+    order.into_iter().map(|(v, _)| *v).collect()
+}
+
+/// Rank (1 = most important) of every variant
+fn ranking(importances: &HashMap<usize, f64>) -> HashMap<usize, usize> {
+    ranking_order(importances).into_iter().enumerate().map(|(i, v)| (v, i + 1)).collect()
+}
+
+/// The n variants with the highest importance
+pub fn top_variants(importances: &HashMap<usize, f64>, n: usize) -> HashSet<usize> {
+    ranking_order(importances).into_iter().take(n).collect()
+}
+
+/// print the stability metrics and the rank change of every variant to stdout
+/// change is previous rank - rank, positive when a variant moved up
+pub fn print_stability(s: &Stability, top: usize, variants: &[variants::Variant]) {
+    println!("#STABILITY");
+    println!("#spearman\ttop{}_jaccard", top);
+    println!("{:?}\t{:?}", s.spearman, s.jaccard);
+    println!("#RANK_CHANGE");
+    println!("#id\trank\tprevious_rank\tchange");
+    for (v, rank, prev) in &s.ranks {
+        println!("{}\t{}\t{}\t{}", variants[*v].id, rank, prev, *prev as i64 - *rank as i64);
+    }
+}
@@ -1,38 +1,421 @@
-pub fn mean(data: &Vec<&f64>) -> f64 {
-    let sum: f64 = data.iter().filter_map(|value| 
-        match **value {
-            x if x >= 0. => Some(x),
-            _ => None 
-        }).sum();
-    let count = data.len();
-    match count {
-        0 => return 0.,
-        _ => {sum / count as f64}
-    }
+use rayon::prelude::*;
 
+/// Mean of all values, negative ones included (see nonnegative_mean for the keep_vars baseline)
+pub fn mean(data: &[&f64]) -> f64 {
+    match data.len() {
+        0 => 0.,
+        n => data.iter().map(|x| **x).sum::<f64>() / n as f64
+    }
 }
 
-pub fn std_deviation(data: &Vec<&f64>) -> f64 {
+/// Population standard deviation
+pub fn std_deviation(data: &[&f64]) -> f64 {
+    if data.is_empty() {
+        return 0.
+    }
     let data_mean = mean(data);
-    let count = data.iter().filter_map(|value| 
-        match **value {
-            x if x >= 0. => Some(x),
-            _ => None 
-        }).count();
-    match count {
-        0 => return 0.,
-        _ => {
-            let variance = data.iter().filter_map(|value| 
-                match **value {
-                    x if x >= 0. => {
-                        let diff = data_mean - x;
-                        Some(diff * diff)
-                    },
-                    _ => None
-            }).sum::<f64>() / count as f64;
-            variance.sqrt()
+    let variance = data.iter().map(|x| (**x - data_mean) * (**x - data_mean)).sum::<f64>() / data.len() as f64;
+    variance.sqrt()
+}
+
+/// Mean with negative values (shuffle penalties) counted as 0, the baseline of the Forest::keep_vars z scores
+pub fn nonnegative_mean(data: &[&f64]) -> f64 {
+    match data.len() {
+        0 => 0.,
+        n => data.iter().map(|x| x.max(0.)).sum::<f64>() / n as f64
+    }
+}
+
+/// Standard deviation of the non-negative values around nonnegative_mean, negative values are left out
+pub fn nonnegative_std_deviation(data: &[&f64]) -> f64 {
+    let data_mean = nonnegative_mean(data);
+    let kept: Vec<f64> = data.iter().map(|x| **x).filter(|x| *x >= 0.).collect();
+    match kept.len() {
+        0 => 0.,
+        n => (kept.iter().map(|x| (data_mean - x) * (data_mean - x)).sum::<f64>() / n as f64).sqrt()
+    }
+}
+
+/// Exact test for Hardy-Weinberg equilibrium (Wigginton, Cutler and Abecasis 2005)
+/// Takes the observed counts of heterozygotes and each homozygote, returns the p-value
+pub fn hwe_exact(obs_hets: usize, obs_hom1: usize, obs_hom2: usize) -> f64 {
+    let obs_homc = obs_hom1.max(obs_hom2);
+    let obs_homr = obs_hom1.min(obs_hom2);
+    let rare_copies = 2 * obs_homr + obs_hets;
+    let genotypes = obs_hets + obs_homc + obs_homr;
+    if genotypes == 0 {
+        return 1.
+    }
+    let mut het_probs: Vec<f64> = vec![0.; rare_copies + 1];
+    // start at the most likely number of heterozygotes and walk out in both directions
+    let mut mid = rare_copies * (2 * genotypes - rare_copies) / (2 * genotypes);
+    if (rare_copies % 2) != (mid % 2) {
+        mid += 1;
+    }
+    het_probs[mid] = 1.;
+    let mut sum = 1.;
+    let mut curr_hets = mid;
+    let mut curr_homr = (rare_copies - mid) / 2;
+    let mut curr_homc = genotypes - curr_hets - curr_homr;
+    while curr_hets > 1 {
+        het_probs[curr_hets - 2] = het_probs[curr_hets] * (curr_hets * (curr_hets - 1)) as f64
+            / (4. * (curr_homr + 1) as f64 * (curr_homc + 1) as f64);
+        sum += het_probs[curr_hets - 2];
+        curr_homr += 1;
+        curr_homc += 1;
+        curr_hets -= 2;
+    }
+    curr_hets = mid;
+    curr_homr = (rare_copies - mid) / 2;
+    curr_homc = genotypes - curr_hets - curr_homr;
+    while curr_hets + 2 <= rare_copies {
+        het_probs[curr_hets + 2] = het_probs[curr_hets] * 4. * curr_homr as f64 * curr_homc as f64
+            / ((curr_hets + 2) * (curr_hets + 1)) as f64;
+        sum += het_probs[curr_hets + 2];
+        curr_homr -= 1;
+        curr_homc -= 1;
+        curr_hets += 2;
+    }
+    let obs_prob = het_probs[obs_hets];
+    let p: f64 = het_probs.iter().filter(|p| **p <= obs_prob).sum::<f64>() / sum;
+    p.min(1.)
+}
+
+/// Fraction of predicted labels that differ from the observed labels
+pub fn misclassification_rate(observed: &[f64], predicted: &[f64]) -> f64 {
+    if observed.is_empty() {
+        return 0.
+    }
+    let wrong = observed.iter().zip(predicted.iter()).filter(|(o, p)| o != p).count();
+    wrong as f64 / observed.len() as f64
+}
+
+/// Mean of the squared differences between observed and predicted values
+pub fn mean_squared_error(observed: &[f64], predicted: &[f64]) -> f64 {
+    if observed.is_empty() {
+        return 0.
+    }
+    observed.iter().zip(predicted.iter()).map(|(o, p)| (o - p) * (o - p)).sum::<f64>() / observed.len() as f64
+}
+
+/// Harrell's concordance index of a risk score against (time, event) survival data
+/// A pair is usable when the shorter time is an event, it is concordant when that subject has the higher risk
+/// Tied risks count as half concordant
+pub fn concordance_index(times: &[f64], events: &[f64], risk: &[f64]) -> f64 {
+    let (mut concordant, mut usable) = (0., 0.);
+    for i in 0..times.len() {
+        if events[i] != 1. {
+            continue
         }
+        for j in 0..times.len() {
+            if times[j] <= times[i] {
+                continue
+            }
+            usable += 1.;
+            if risk[i] > risk[j] {
+                concordant += 1.;
+            } else if risk[i] == risk[j] {
+                concordant += 0.5;
+            }
+        }
+    }
+    if usable == 0. {
+        0.5
+    } else {
+        concordant / usable
+    }
+}
+
+/// Pearson correlation of two samples, 0 when either has no variance
+pub fn correlation(x: &[f64], y: &[f64]) -> f64 {
+    let n = x.len() as f64;
+    if n < 2. {
+        return 0.
+    }
+    let (mx, my) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let (mut sxy, mut sxx, mut syy) = (0., 0., 0.);
+    for (a, b) in x.iter().zip(y.iter()) {
+        sxy += (a - mx) * (b - my);
+        sxx += (a - mx) * (a - mx);
+        syy += (b - my) * (b - my);
     }
+    if sxx <= 0. || syy <= 0. {
+        return 0.
+    }
+    sxy / (sxx * syy).sqrt()
 }
 
+/// Squared Pearson correlation of two samples
+pub fn correlation_squared(x: &[f64], y: &[f64]) -> f64 {
+    correlation(x, y).powi(2)
+}
 
+/// Ranks (1-based) of the values, ties get their average rank
+pub fn ranks(x: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..x.len()).collect();
+    order.sort_by(|a, b| x[*a].partial_cmp(&x[*b]).unwrap_or(std::cmp::Ordering::Equal));
+    let mut r: Vec<f64> = vec![0.; x.len()];
+    let mut i: usize = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && x[order[j + 1]] == x[order[i]] {
+            j += 1;
+        }
+        let average = (i + j) as f64 / 2. + 1.;
+        for k in &order[i..=j] {
+            r[*k] = average;
+        }
+        i = j + 1;
+    }
+    r
+}
+
+/// Value at quantile q (0-1) of sorted values, interpolated linearly between order statistics
+/// NaN for no values
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN
+    }
+    let h = q.clamp(0., 1.) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (h.floor() as usize, h.ceil() as usize);
+    sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+pub fn median(x: &[f64]) -> f64 {
+    let mut sorted = x.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    quantile(&sorted, 0.5)
+}
+
+/// Area under the ROC curve of scores for 0/1 labels (Mann-Whitney), 0.5 when a class is absent
+pub fn auc(labels: &[f64], scores: &[f64]) -> f64 {
+    let r = ranks(scores);
+    let n_pos = labels.iter().filter(|l| **l == 1.).count() as f64;
+    let n_neg = labels.len() as f64 - n_pos;
+    if n_pos == 0. || n_neg == 0. {
+        return 0.5
+    }
+    let rank_sum: f64 = r.iter().zip(labels.iter()).filter(|(_, l)| **l == 1.).map(|(r, _)| r).sum();
+    (rank_sum - n_pos * (n_pos + 1.) / 2.) / (n_pos * n_neg)
+}
+
+/// Coefficient of determination of predicted against observed values
+pub fn r_squared(observed: &[f64], predicted: &[f64]) -> f64 {
+    let n = observed.len() as f64;
+    if n == 0. {
+        return 0.
+    }
+    let mean = observed.iter().sum::<f64>() / n;
+    let ss_tot: f64 = observed.iter().map(|o| (o - mean) * (o - mean)).sum();
+    let ss_res: f64 = observed.iter().zip(predicted.iter()).map(|(o, p)| (o - p) * (o - p)).sum();
+    if ss_tot == 0. {
+        return 0.
+    }
+    1. - ss_res / ss_tot
+}
+
+/// Pearson chi-square statistic of a contingency table and its degrees of freedom
+/// Rows may be ragged (missing cells count as 0), empty rows and columns do not add degrees of freedom
+pub fn chi_square_table(table: &[Vec<f64>]) -> (f64, usize) {
+    let n_cols = table.iter().map(|r| r.len()).max().unwrap_or(0);
+    let row_totals: Vec<f64> = table.iter().map(|r| r.iter().sum()).collect();
+    let col_totals: Vec<f64> = (0..n_cols).map(|c| table.iter().map(|r| *r.get(c).unwrap_or(&0.)).sum()).collect();
+    let n: f64 = row_totals.iter().sum();
+    let rows = row_totals.iter().filter(|t| **t > 0.).count();
+    let cols = col_totals.iter().filter(|t| **t > 0.).count();
+    if rows < 2 || cols < 2 {
+        return (0., 0)
+    }
+    let mut stat: f64 = 0.;
+    for (r, row) in table.iter().enumerate() {
+        for (c, col_total) in col_totals.iter().enumerate() {
+            let expected = row_totals[r] * col_total / n;
+            if expected > 0. {
+                let observed = *row.get(c).unwrap_or(&0.);
+                stat += (observed - expected) * (observed - expected) / expected;
+            }
+        }
+    }
+    (stat, (rows - 1) * (cols - 1))
+}
+
+/// Upper tail probability of the chi-square distribution with df degrees of freedom
+pub fn chi_square_sf(x: f64, df: f64) -> f64 {
+    if x <= 0. {
+        return 1.
+    }
+    gamma_q(df / 2., x / 2.)
+}
+
+/// Regularized upper incomplete gamma function Q(a, x)
+/// Series expansion below a + 1, continued fraction (modified Lentz) above
+fn gamma_q(a: f64, x: f64) -> f64 {
+    let ln_prefix = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1. {
+        let (mut term, mut sum, mut ap) = (1. / a, 1. / a, a);
+        for _ in 0..500 {
+            ap += 1.;
+            term *= x / ap;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break
+            }
+        }
+        return (1. - sum * ln_prefix.exp()).clamp(0., 1.)
+    }
+    let tiny = 1e-300;
+    let mut b = x + 1. - a;
+    let mut c = 1. / tiny;
+    let mut d = 1. / b;
+    let mut h = d;
+    for i in 1..500 {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.;
+        d = an * d + b;
+        if d.abs() < tiny {
+            d = tiny;
+        }
+        c = b + an / c;
+        if c.abs() < tiny {
+            c = tiny;
+        }
+        d = 1. / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.).abs() < 1e-15 {
+            break
+        }
+    }
+    (ln_prefix.exp() * h).clamp(0., 1.)
+}
+
+/// Natural log of the gamma function (Lanczos approximation)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [76.18009172947146, -86.50532032941677, 24.01409824083091,
+        -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    for (j, c) in COEFFICIENTS.iter().enumerate() {
+        ser += c / (x + 1. + j as f64);
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+/// Largest eigenvalue and its unit eigenvector of a symmetric matrix by power iteration
+/// The matrix is shifted by its largest absolute row sum so the iteration finds the largest
+/// eigenvalue rather than the one of largest magnitude
+pub fn leading_eigen(m: &[Vec<f64>]) -> (f64, Vec<f64>) {
+    let n = m.len();
+    let shift: f64 = m.iter().map(|row| row.iter().map(|x| x.abs()).sum::<f64>()).fold(0., f64::max);
+    let mut v: Vec<f64> = (0..n).map(|i| 1. + (i % 7) as f64 / 7.).collect();
+    let mut lambda: f64 = 0.;
+    for _ in 0..1000 {
+        let w: Vec<f64> = m.par_iter().zip(v.par_iter())
+            .map(|(row, vi)| row.iter().zip(v.iter()).map(|(a, b)| a * b).sum::<f64>() + shift * vi)
+            .collect();
+        let norm = w.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm == 0. {
+            return (0., vec![0.; n])
+        }
+        let next: Vec<f64> = w.iter().map(|x| x / norm).collect();
+        let new_lambda: f64 = next.iter().zip(m.iter())
+            .map(|(vi, row)| vi * row.iter().zip(next.iter()).map(|(a, b)| a * b).sum::<f64>())
+            .sum();
+        let converged = (new_lambda - lambda).abs() <= 1e-10 * new_lambda.abs().max(1.);
+        v = next;
+        lambda = new_lambda;
+        if converged {
+            break
+        }
+    }
+    (lambda, v)
+}
+
+/// Ordinary least squares coefficients of y on the columns of x (one row of predictors per observation)
+/// Solves the normal equations by Gaussian elimination with partial pivoting, add a column of 1s to x for
+/// an intercept; None when the predictors are collinear
+pub fn least_squares(x: &[Vec<f64>], y: &[f64]) -> Option<Vec<f64>> {
+    let k = x.first().map_or(0, |r| r.len());
+    // augmented matrix [X'X | X'y]
+    let mut a: Vec<Vec<f64>> = vec![vec![0.; k + 1]; k];
+    for (row, yi) in x.iter().zip(y.iter()) {
+        for i in 0..k {
+            for j in 0..k {
+                a[i][j] += row[i] * row[j];
+            }
+            a[i][k] += row[i] * yi;
+        }
+    }
+    let scale: f64 = (0..k).map(|i| a[i][i].abs()).fold(0., f64::max);
+    for col in 0..k {
+        let pivot = (col..k).max_by(|p, q| a[*p][col].abs().partial_cmp(&a[*q][col].abs()).unwrap())?;
+        if a[pivot][col].abs() <= 1e-12 * scale.max(1.) {
+            return None
+        }
+        a.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for (r, row) in a.iter_mut().enumerate() {
+            if r != col {
+                let factor = row[col] / pivot_row[col];
+                for (x, p) in row[col..].iter_mut().zip(pivot_row[col..].iter()) {
+                    *x -= factor * p;
+                }
+            }
+        }
+    }
+    Some((0..k).map(|i| a[i][k] / a[i][i]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * b.abs().max(1e-300)
+    }
+
+    #[test]
+    fn nonnegative_moments_skip_penalties() {
+        let values = [2., -1., 4., 0.];
+        let data: Vec<&f64> = values.iter().collect();
+        assert!(close(nonnegative_mean(&data), 1.5));
+        assert!(close(nonnegative_std_deviation(&data), ((0.25 + 6.25 + 2.25) / 3f64).sqrt()));
+        assert!(close(mean(&data), 1.25));
+    }
+
+    #[test]
+    fn hwe_exact_p_values() {
+        assert!(close(hwe_exact(57, 14, 29), 0.15068007651576146));
+        assert!(close(hwe_exact(50, 25, 25), 1.));
+        assert!(close(hwe_exact(0, 50, 50), 1.114224180581398e-30));
+        assert!(close(hwe_exact(10, 0, 0), 0.00690640628721124));
+        assert_eq!(hwe_exact(0, 0, 0), 1.);
+    }
+
+    #[test]
+    fn prediction_metrics() {
+        assert!(close(auc(&[0., 0., 1., 1.], &[0.1, 0.4, 0.35, 0.8]), 0.75));
+        assert!(close(auc(&[0., 1.], &[0.5, 0.5]), 0.5));
+        assert!(close(auc(&[1., 1.], &[0.2, 0.5]), 0.5));
+        assert!(close(r_squared(&[1., 2., 3.], &[1., 2., 3.]), 1.));
+        assert_eq!(r_squared(&[1., 2., 3.], &[2., 2., 2.]), 0.);
+        assert!(close(r_squared(&[1., 2., 3.], &[3., 2., 1.]), -3.));
+        assert!(close(correlation(&[1., 2., 3.], &[2., 4., 6.]), 1.));
+        assert!(close(correlation(&[1., 2., 3.], &[3., 2., 1.]), -1.));
+        assert_eq!(correlation(&[1., 2., 3.], &[5., 5., 5.]), 0.);
+    }
+
+    #[test]
+    fn least_squares_fits() {
+        // y = 2 - x1 + 0.5 x2 exactly
+        let x: Vec<Vec<f64>> = (0..6).map(|i| vec![1., i as f64, (i * i % 5) as f64]).collect();
+        let y: Vec<f64> = x.iter().map(|r| 2. - r[1] + 0.5 * r[2]).collect();
+        let b = least_squares(&x, &y).unwrap();
+        for (bi, ei) in b.iter().zip([2., -1., 0.5].iter()) {
+            assert!((bi - ei).abs() < 1e-9);
+        }
+        let collinear: Vec<Vec<f64>> = (0..6).map(|i| vec![1., i as f64, 2. * i as f64]).collect();
+        assert!(least_squares(&collinear, &y).is_none());
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Population structure
+//! Genotype principal components from the kinship (genomic relationship) matrix of the active subjects
+//! and variants, and ancestry clusters from the components
+//! The kinship matrix is dense in the number of subjects (at most MAX_KINSHIP_SUBJECTS), variants are streamed
+//! into it in blocks; LD prune the variants first so the components do not follow single regions
+
+use crate::filters;
+use crate::matrix;
+use crate::statistics;
+
+use rand::{Rng, thread_rng};
+use rayon::prelude::*;
+
+/// Largest number of subjects for the dense kinship matrix (8 bytes per pair)
+pub const MAX_KINSHIP_SUBJECTS: usize = 20000;
+
+/// Number of variants standardized at a time when adding them to the kinship matrix
+const KINSHIP_BLOCK: usize = 512;
+
+/// Kinship matrix of the active subjects over the active variants
+/// Carrier calls (0/1) are standardized per variant by the carrier frequency m and sd sqrt(m(1-m)), dosages (0/1/2)
+/// by 2p and sqrt(2p(1-p)) for allele frequency p (see filters for the coding); missing calls count as the mean
+/// and monomorphic variants are skipped
+pub fn kinship(gm: &matrix::GenoMatrix) -> Result<Vec<Vec<f64>>, String> {
+    let subjects = gm.sample_indices();
+    let n = subjects.len();
+    if n > MAX_KINSHIP_SUBJECTS {
+        return Err(format!("{} samples exceed the {} the kinship matrix allows, give precomputed components with --pc-file", n, MAX_KINSHIP_SUBJECTS))
+    }
+    let mut k: Vec<Vec<f64>> = vec![vec![0.; n]; n];
+    let mut n_vars = 0;
+    let copies = if filters::is_dosage_coded(gm) { 2. } else { 1. };
+    for block in gm.genotype_indices().chunks(KINSHIP_BLOCK) {
+        let z: Vec<Vec<f64>> = block.par_iter().filter_map(|g| standardize(gm, subjects, *g, copies)).collect();
+        n_vars += z.len();
+        k.par_iter_mut().enumerate().for_each(|(i, row)| {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x += z.iter().map(|v| v[i] * v[j]).sum::<f64>();
+            }
+        });
+    }
+    let scale = 1. / n_vars.max(1) as f64;
+    k.par_iter_mut().for_each(|row| row.iter_mut().for_each(|x| *x *= scale));
+    Ok(k)
+}
+
+/// Standardized calls of a variant over the subjects (copies is 1 for carrier and 2 for dosage coding),
+/// None if it is monomorphic or never called
+fn standardize(gm: &matrix::GenoMatrix, subjects: &[usize], g: usize, copies: f64) -> Option<Vec<f64>> {
+    let calls: Vec<u8> = subjects.iter().map(|s| *gm.genotype(*s, g)).collect();
+    let called: Vec<f64> = calls.iter().filter(|c| **c < matrix::MISSING_GENOTYPE).map(|c| *c as f64).collect();
+    if called.is_empty() {
+        return None
+    }
+    let m = called.iter().sum::<f64>() / called.len() as f64;
+    let p = m / copies;
+    let sd = (copies * p * (1. - p)).sqrt();
+    if sd <= 0. || sd.is_nan() {
+        return None
+    }
+    Some(calls.iter().map(|c| if *c < matrix::MISSING_GENOTYPE { (*c as f64 - m) / sd } else { 0. }).collect())
+}
+
+/// The leading n principal components of the genotypes, from the eigenvectors of the kinship matrix
+/// scaled by the square root of their eigenvalues
+/// Returns one row per matrix row, NaN for subjects that are not active
+pub fn principal_components(gm: &matrix::GenoMatrix, n: usize) -> Result<matrix::Covariates, String> {
+    let mut k = kinship(gm)?;
+    let mut components: Vec<Vec<f64>> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let (lambda, v) = statistics::leading_eigen(&k);
+        let scale = lambda.max(0.).sqrt();
+        components.push(v.iter().map(|x| x * scale).collect());
+        // deflate so the next power iteration finds the following component
+        k.par_iter_mut().enumerate().for_each(|(i, row)| {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x -= lambda * v[i] * v[j];
+            }
+        });
+    }
+    let mut values: Vec<Vec<f64>> = vec![vec![f64::NAN; n]; gm.ids.len()];
+    for (i, s) in gm.sample_indices().iter().enumerate() {
+        values[*s] = components.iter().map(|c| c[i]).collect();
+    }
+    Ok(matrix::Covariates {
+        names: (1..=n).map(|c| format!("PC{}", c)).collect(),
+        values
+    })
+}
+
+/// Ancestry clusters of the active subjects by k-means (k-means++ seeding) on their covariates
+/// Returns a label per matrix row, inactive subjects and subjects with missing values get label k
+pub fn ancestry_clusters(gm: &matrix::GenoMatrix, covariates: &matrix::Covariates, k: usize) -> Vec<usize> {
+    let subjects: Vec<usize> = gm.sample_indices().iter()
+        .filter(|s| covariates.values[**s].iter().all(|v| v.is_finite()))
+        .copied()
+        .collect();
+    let points: Vec<&Vec<f64>> = subjects.iter().map(|s| &covariates.values[*s]).collect();
+    let mut labels: Vec<usize> = vec![k; gm.ids.len()];
+    for (s, l) in subjects.iter().zip(kmeans(&points, k)) {
+        labels[*s] = l;
+    }
+    labels
+}
+
+/// Lloyd's k-means with k-means++ seeding, returns the cluster of each point
+fn kmeans(points: &[&Vec<f64>], k: usize) -> Vec<usize> {
+    if points.is_empty() || k == 0 {
+        return vec![0; points.len()]
+    }
+    let mut rng = thread_rng();
+    let distance = |a: &[f64], b: &[f64]| a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<f64>();
+    let mut centres: Vec<Vec<f64>> = vec![points[rng.gen_range(0..points.len())].to_vec()];
+    while centres.len() < k.min(points.len()) {
+        let d: Vec<f64> = points.iter()
+            .map(|p| centres.iter().map(|c| distance(p, c)).fold(f64::INFINITY, f64::min))
+            .collect();
+        let total: f64 = d.iter().sum();
+        if total <= 0. {
+            break
+        }
+        let mut target = rng.gen::<f64>() * total;
+        let next = d.iter().position(|di| { target -= di; target <= 0. }).unwrap_or(points.len() - 1);
+        centres.push(points[next].to_vec());
+    }
+    let mut labels: Vec<usize> = vec![usize::MAX; points.len()];
+    for _ in 0..100 {
+        let next: Vec<usize> = points.par_iter().map(|p| {
+            (0..centres.len()).min_by(|a, b| distance(p, &centres[*a]).partial_cmp(&distance(p, &centres[*b])).unwrap()).unwrap()
+        }).collect();
+        if next == labels {
+            break
+        }
+        labels = next;
+        for (c, centre) in centres.iter_mut().enumerate() {
+            let members: Vec<&&Vec<f64>> = points.iter().zip(labels.iter()).filter(|(_, l)| **l == c).map(|(p, _)| p).collect();
+            if members.is_empty() {
+                continue
+            }
+            for (d, x) in centre.iter_mut().enumerate() {
+                *x = members.iter().map(|m| m[d]).sum::<f64>() / members.len() as f64;
+            }
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    #[test]
+    fn kinship_standardizes_carriers() {
+        let rows: Vec<(f64, Vec<u8>)> = vec![(0., vec![0, 1, 1]), (1., vec![1, 1, 0]), (0., vec![1, 1, 3]), (1., vec![0, 1, 0])];
+        let k = kinship(&test_utils::matrix(&rows, matrix::Outcome::Binary)).unwrap();
+        // variant 0 has m = 0.5, z = (-1, 1, 1, -1); variant 1 is monomorphic; variant 2 has m = 1/3 and is 0 where missing
+        let z2 = [(1. - 1. / 3.) / (2f64 / 9.).sqrt(), (-1. / 3.) / (2f64 / 9.).sqrt(), 0., (-1. / 3.) / (2f64 / 9.).sqrt()];
+        let z0 = [-1., 1., 1., -1.];
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((k[i][j] - (z0[i] * z0[j] + z2[i] * z2[j]) / 2.).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn kinship_standardizes_dosages() {
+        let rows: Vec<(f64, Vec<u8>)> = vec![(0., vec![0]), (1., vec![2]), (0., vec![1]), (1., vec![1])];
+        let k = kinship(&test_utils::matrix(&rows, matrix::Outcome::Binary)).unwrap();
+        // mean 1, p = 0.5, sd sqrt(0.5), z = (-sqrt(2), sqrt(2), 0, 0)
+        let z = [-2f64.sqrt(), 2f64.sqrt(), 0., 0.];
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((k[i][j] - z[i] * z[j]).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn kmeans_separates_clusters() {
+        let points: Vec<Vec<f64>> = (0..20).map(|i| vec![if i < 10 { 0. } else { 10. } + (i % 5) as f64 * 0.1, 1.]).collect();
+        let refs: Vec<&Vec<f64>> = points.iter().collect();
+        let labels = kmeans(&refs, 2);
+        assert!(labels[..10].iter().all(|l| *l == labels[0]));
+        assert!(labels[10..].iter().all(|l| *l == labels[10]));
+        assert_ne!(labels[0], labels[10]);
+        assert_eq!(kmeans(&refs[..0], 2), Vec::<usize>::new());
+    }
+}
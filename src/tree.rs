@@ -4,10 +4,14 @@
 //! Decision tree algorithm
 //! Defines an abstract tree represented by nested nodes
 
+use crate::criterion;
 use crate::matrix;
 use crate::utils;
 use crate::statistics;
+use crate::variants;
 
+use rand::thread_rng;
+use rand::seq::SliceRandom;
 use std::collections::HashMap;
 
 
@@ -15,60 +19,151 @@ use std::collections::HashMap;
 /// Where the top level node is allocated to the stack, and subsequent
 /// nodes on the heap. In order to 'draw' a tree, one must walk down recursive nodes
 pub struct Node {
-    pub score: f64, // gini if binary outcome, sd reduction if continuous
+    pub score: f64, // gini if binary/categorical outcome, sd reduction if continuous, log-rank if survival
     pub is_empty: bool,
     pub n: usize, // Number of subjects in tree
     pub neg: bool,
     pub var: usize,
+    pub threshold: u8, // genotypes up to the threshold go left, called genotypes above it go right
     pub node_n: usize, // Number of subjects in node, needed for importance calculation of parent node
+    pub value: Vec<f64>, // prediction at this node: class proportions, the mean if continuous, cumulative hazard if survival
     pub left: Option<Box<Node>>,
     pub right: Option<Box<Node>>
 }
 
+/// Settings that control how a single tree is grown
+pub struct TreeParameters {
+    pub max_depth: i32,
+    pub outcome: matrix::Outcome,
+    pub n_classes: usize,
+    pub time_grid: Vec<f64>, // time points of the cumulative hazard (survival only)
+    pub node_mtry: Option<f64>, // fraction of the tree's variants drawn as candidates at each split (per-node mtry)
+    pub n_forced: usize, // the last variants of the slice (covariates) are candidates at every split
+    pub stopping: StoppingRules,
+    pub criterion: Box<dyn criterion::SplitCriterion>,
+    pub selection: SplitSelection
+}
+
+/// How the split variant of a node is chosen
+/// Impurity: the best shuffle-corrected criterion score over the candidates
+/// ConditionalInference: the smallest association test p-value (asymptotic, or from the given number of
+/// permutations), a node is only split when the Bonferroni-adjusted p-value is below alpha
+/// ExtraTrees: the best score over n_candidates random variants, each split at a random genotype threshold
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SplitSelection {
+    Impurity,
+    ConditionalInference { alpha: f64, permutations: usize },
+    ExtraTrees { n_candidates: usize }
+}
+
+/// Rules that stop a node from splitting, a rule is skipped when it is None
+/// min_node_size = minimum number of subjects in a node to attempt a split
+/// min_leaf_size = minimum number of subjects on each side of a split
+/// min_impurity_decrease = minimum decrease of the split score (Gini decrease, sd reduction or log-rank statistic)
+/// max_leaves = maximum number of leaves in a tree, nodes are split depth-first until it is reached
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StoppingRules {
+    pub min_node_size: Option<usize>,
+    pub min_leaf_size: Option<usize>,
+    pub min_impurity_decrease: Option<f64>,
+    pub max_leaves: Option<usize>
+}
+
 /// tree data
 /// Contains data that is passed to create
 /// a decision tree
+/// Genotypes are not copied into the node, the column of a candidate variant is gathered for the
+/// node's subjects when it is scored
 pub struct NodeData<'a> {
-    pub phenos: Vec<&'a f64>, // phenotype vector
+    pub phenos: Vec<&'a f64>, // phenotype vector (times if survival)
     pub phenos_shuffle: Vec<&'a f64>, // vector of shuffled phenotypes (randomly, for impurity correction)
-    pub genos: Vec<Vec<&'a u8>> // nested vector of genotypes
+    pub events: Vec<&'a f64>, // event indicators if survival, otherwise empty
+    pub events_shuffle: Vec<&'a f64>, // event indicators shuffled together with phenos_shuffle
+    pub rows: Vec<usize>, // position of each subject of the node in the tree's sample, in the order of phenos
+    pub genos: &'a matrix::SliceGenotypes<'a>
 }
 
 impl Node {
 
     /// The function that creates a tree
-    pub fn grow(node_data: NodeData, max_depth: i32, ms: matrix::GenoMatrixSlice, continuous_outcome: bool) -> Self {
+    pub fn grow(node_data: NodeData, ms: &matrix::GenoMatrixSlice, params: &TreeParameters) -> Self {
         let depth: i32 = 0;
         let node_n = node_data.phenos.len();
-        return Node::new_node(&node_data, &ms, &max_depth, &depth, node_n, &continuous_outcome);
+        let mut leaves: usize = 1;
+        Node::new_node(&node_data, ms, params, &depth, node_n, &mut leaves)
     }
 
     /// An empty node, called internally to allow for terminal nodes that stop growth
     pub fn empty_node() -> Self {
         Node {
-            score: std::f64::NAN,
+            score: f64::NAN,
             is_empty: true,
             n: 0,
             neg: true,
             var: 0,
+            threshold: 0,
             node_n: 0,
+            value: Vec::new(),
             left: None,
             right: None
         }
     }
 
+    /// A terminal node where growth stopped, it carries no split but keeps its prediction
+    fn leaf(value: Vec<f64>, node_n: usize) -> Self {
+        let mut node = Node::empty_node();
+        node.value = value;
+        node.node_n = node_n;
+        node
+    }
+
+    /// Predict an outcome for subject s by walking down the tree
+    /// Subjects stop at the deepest node they can reach (a missing or unseen genotype stops the walk)
+    pub fn predict(&self, gm: &matrix::GenoMatrix, s: usize) -> Option<&Vec<f64>> {
+        let here = if self.value.is_empty() { None } else { Some(&self.value) };
+        if self.is_empty {
+            return here
+        }
+        let child = match *gm.genotype(s, self.var) {
+            g if g >= matrix::MISSING_GENOTYPE => None,
+            g if g <= self.threshold => self.left.as_ref(),
+            _ => self.right.as_ref()
+        };
+        match child {
+            Some(c) => c.predict(gm, s).or(here),
+            None => here
+        }
+    }
+
+    /// Node where subject s ends up, following the same walk as predict
+    pub fn terminal(&self, gm: &matrix::GenoMatrix, s: usize) -> &Node {
+        if self.is_empty {
+            return self
+        }
+        let child = match *gm.genotype(s, self.var) {
+            g if g >= matrix::MISSING_GENOTYPE => None,
+            g if g <= self.threshold => self.left.as_ref(),
+            _ => self.right.as_ref()
+        };
+        match child {
+            Some(c) if !c.value.is_empty() => c.terminal(gm, s),
+            _ => self
+        }
+    }
+
     /// Print a tree to stdout by iterating over recursive nodes
-    pub fn print(&self, above: &usize, side: &str) {
+    /// Each split is listed with the split above it (the root has parent 0 and side 0) and the variant metadata
+    pub fn print(&self, above: &usize, side: &str, variants: &[variants::Variant]) {
         if !self.is_empty {
-            println!("{:?}\t{:?}\t{}\t{:?}", self.var, above, side, self.score);
+            let parent_id = if side == "0" { "NA" } else { variants[*above].id.as_str() };
+            println!("{:?}\t{:?}\t{}\t{:?}\t{}\t{}\t{}", self.var, above, side, self.score,
+                variants[self.var].id, parent_id, variants[self.var].describe());
         }
-        match &self.left {
-            Some(n) => n.print(&self.var, &"left"),
-            None => ()
+        if let Some(n) = &self.left {
+            n.print(&self.var, "left", variants);
         }
-        match &self.right {
-            Some(n) => n.print(&self.var, &"right"),
-            None => ()
+        if let Some(n) = &self.right {
+            n.print(&self.var, "right", variants);
         }
     }
 
@@ -81,28 +176,22 @@ impl Node {
         /// Nested function to do the calculation
         fn imp(n: &Node, vi: &mut HashMap<usize, Vec<f64>>) {
             let mut importance: f64 = (n.node_n as f64 / n.n as f64) * (n.score);
-            match &n.left {
-                Some(nl) => {
-                    if &nl.score > &0. {
-                        importance -= (&nl.node_n / n.n) as f64 * (&nl.score);
-                        imp(&nl, vi);
-                    }
-                },
-                None => ()
-            };
-            match &n.right {
-                Some(nr) => {
-                    if &nr.score > &0. {
-                        importance -= (&nr.node_n / n.n) as f64 * (&nr.score);
-                        imp(&nr, vi);
-                    }
-                },
-                None => ()
-            };
+            if let Some(nl) = &n.left {
+                if nl.score > 0. {
+                    importance -= (nl.node_n / n.n) as f64 * nl.score;
+                    imp(nl, vi);
+                }
+            }
+            if let Some(nr) = &n.right {
+                if nr.score > 0. {
+                    importance -= (nr.node_n / n.n) as f64 * nr.score;
+                    imp(nr, vi);
+                }
+            }
             if n.neg & (importance > 0.) {
                 // was this calculated based on shuffled subjects?
                 // If so, it is a negative contribution
-                importance = importance * -1.;
+                importance = -importance;
             } else if importance < 0. {
                 importance = 0.
             }
@@ -117,168 +206,339 @@ impl Node {
             }
         }
         imp(self, &mut var_imp);
-        return var_imp
+        var_imp
     }
 
     /// Recursive function for building the tree
     /// Kicked off when a new tree is created
-    fn new_node(node_data: &NodeData, ms: &matrix::GenoMatrixSlice, max_depth: &i32, depth: &i32, n: usize, continuous_outcome: &bool) -> Node {
+    /// leaves counts the leaves of the tree so far, each split adds one
+    fn new_node(node_data: &NodeData, ms: &matrix::GenoMatrixSlice, params: &TreeParameters, depth: &i32, n: usize, leaves: &mut usize) -> Node {
         let new_depth = depth + 1;
-        let mut best_score_index: usize = 0;
-        let mut scores: Vec<f64> = Vec::new(); // Vector of per-genotype scores
-        for k in &node_data.genos {
-            // Each genotype vector has score calculated based on the actual phenos (score1)
-            // and the shuffled phenotypes (score2)
-            if *continuous_outcome {
-                let score = calc_sdr(&node_data.phenos, k);
-                let score2 = calc_sdr(&node_data.phenos_shuffle, k);
-                match score {
-                    // if shuffled pheno score is better than the actual pheno, then use that one
-                    // but make it negative to indicate that it is to be a penalty rather than a contributor
-                    // to overall importance
-                    _x if score > score2 => {scores.push(score)},
-                    _ => {scores.push(score2 * -1.)}
-                };
-                best_score_index = utils::get_max_index(&scores);
-                if scores[best_score_index].abs() == 0. {
-                    return Node::empty_node();
-                }
-
-            } else {
-                let score = calc_gini(&node_data.phenos, k);
-                let score2 = calc_gini(&node_data.phenos_shuffle, k);
-                if score < score2 {
-                    scores.push(score);
-                }
-                else { 
-                    // if shuffled pheno score is better than the actual pheno, then use that one
-                    // but make it negative to indicate that it is to be a penalty rather than a contributor
-                    // to overall importance
-                    scores.push(-1. * score2);
-                }
-
-                best_score_index = utils::get_min_index(&scores); // get lowest Gini score
-            };
+        let value = node_value(node_data, params);
+        let rules = &params.stopping;
+        let node_size = node_data.phenos.len();
+        if rules.min_node_size.is_some_and(|min| node_size < min) || rules.max_leaves.is_some_and(|max| *leaves >= max) {
+            return Node::leaf(value, node_size);
         }
-        if scores.len() == 0 {
-            return Node::empty_node();
+        let mut candidates = candidate_variants(ms.genotype_ids.len(), params.n_forced, params.node_mtry);
+        let extra_trees = matches!(params.selection, SplitSelection::ExtraTrees { .. });
+        // extra trees check the min leaf size on the variants they draw, the others are never read
+        if let (Some(min_leaf), false) = (rules.min_leaf_size, extra_trees) {
+            candidates.retain(|c| {
+                let (n_left, n_right) = side_counts(&node_data.genotypes(*c), 0);
+                n_left >= min_leaf && n_right >= min_leaf
+            });
         }
-        let mut score = scores[best_score_index];
+        let criterion = &params.criterion;
+        let selected = match params.selection {
+            SplitSelection::Impurity => select_by_impurity(node_data, &candidates, criterion.as_ref()),
+            SplitSelection::ConditionalInference { alpha, permutations } =>
+                select_by_test(node_data, &candidates, params, alpha, permutations),
+            SplitSelection::ExtraTrees { n_candidates } =>
+                select_at_random(node_data, &candidates, params, n_candidates)
+        };
+        let (best, threshold, mut score) = match selected {
+            Some(split) => split,
+            None => return Node::leaf(value, node_size)
+        };
         let mut neg: bool = false;
         if score < 0. {
             neg = true;
-            score = score * -1.;
+            score = -score;
+        }
+        if let Some(min_decrease) = rules.min_impurity_decrease {
+            // a negated score comes from the shuffled phenotypes, so is its parent impurity
+            let parent = if neg { &node_data.phenos_shuffle } else { &node_data.phenos };
+            if criterion.decrease(parent, score) < min_decrease {
+                return Node::leaf(value, node_size);
+            }
         }
-        let left_indices: &Vec<bool> = &node_data.genos[best_score_index].iter().map(|r| **r==0).collect();
-        let right_indices: &Vec<bool> = &node_data.genos[best_score_index].iter().map(|r| **r==1).collect();
+        let best_genos = node_data.genotypes(best);
+        let left_indices: Vec<bool> = best_genos.iter().map(|r| **r <= threshold).collect();
+        let right_indices: Vec<bool> = best_genos.iter().map(|r| **r > threshold && **r < matrix::MISSING_GENOTYPE).collect();
         let new_node_data = &node_data.split(&left_indices, &right_indices);
-        if depth > max_depth {
+        if *depth > params.max_depth {
             return Node {
-                score: score,
+                score,
                 is_empty: false,
-                n: n,
-                neg: neg,
+                n,
+                neg,
                 node_n: node_data.phenos.len(),
-                var: ms.genotype_ids[best_score_index],
+                var: ms.genotype_ids[best],
+                threshold,
+                value,
                 left: None,
                 right: None
             };
         }
+        *leaves += 1;
+        let left = Node::new_node(&new_node_data.0, ms, params, &new_depth, n, leaves);
+        let right = Node::new_node(&new_node_data.1, ms, params, &new_depth, n, leaves);
         Node {
-            score: score,
+            score,
             is_empty: false,
-            n: n,
-            neg: neg,
+            n,
+            neg,
             node_n: node_data.phenos.len(),
-            var: ms.genotype_ids[best_score_index],
-            left: Some(Box::new(Node::new_node(&new_node_data.0, ms, max_depth, &new_depth, n, continuous_outcome))),
-            right: Some(Box::new(Node::new_node(&new_node_data.1, ms, max_depth, &new_depth, n, continuous_outcome)))
+            var: ms.genotype_ids[best],
+            threshold,
+            value,
+            left: Some(Box::new(left)),
+            right: Some(Box::new(right))
         }
     }
 }
 
 
+/// Impurity-based selection over the candidates (positions in the tree's variants)
+/// Returns the chosen position, the genotype threshold and the score, negative if the shuffled
+/// phenotypes scored better, or None when the node should not be split
+fn select_by_impurity(node_data: &NodeData, candidates: &[usize], criterion: &dyn criterion::SplitCriterion) -> Option<(usize, u8, f64)> {
+    let mut scores: Vec<f64> = Vec::new(); // Vector of per-genotype scores
+    for k in candidates.iter().map(|c| node_data.genotypes(*c)) {
+        // Each genotype vector has score calculated based on the actual phenos (score1)
+        // and the shuffled phenotypes (score2)
+        // Dosages are split at threshold 0 like the node itself, so the criteria only see 0/1
+        if k.iter().any(|x| **x > 1 && **x < matrix::MISSING_GENOTYPE) {
+            let recoded = recode(&k, 0);
+            scores.push(shuffle_corrected_score(node_data, &recoded.iter().collect::<Vec<&u8>>(), criterion));
+        } else {
+            scores.push(shuffle_corrected_score(node_data, &k, criterion));
+        }
+    }
+    if scores.is_empty() {
+        return None
+    }
+    let best_score_index = if criterion.minimize() {
+        utils::get_min_index(&scores) // get lowest impurity
+    } else {
+        // largest reduction / test statistic, no split if nothing separates the subjects
+        utils::get_max_index(&scores)
+    };
+    if !criterion.minimize() && scores[best_score_index].abs() == 0. {
+        return None
+    }
+    Some((candidates[best_score_index], 0, scores[best_score_index]))
+}
+
+/// Criterion score of a genotype vector for the actual phenotypes, or the negated score of the
+/// shuffled phenotypes if those scored better
+fn shuffle_corrected_score(node_data: &NodeData, g: &[&u8], criterion: &dyn criterion::SplitCriterion) -> f64 {
+    let score = criterion.score(&node_data.phenos, &node_data.events, g);
+    let score2 = criterion.score(&node_data.phenos_shuffle, &node_data.events_shuffle, g);
+    let real_is_better = if criterion.minimize() { score < score2 } else { score > score2 };
+    if real_is_better {
+        score
+    } else {
+        // if shuffled pheno score is better than the actual pheno, then use that one
+        // but make it negative to indicate that it is to be a penalty rather than a contributor
+        // to overall importance
+        -score2
+    }
+}
+
+/// Conditional-inference selection: the variant is chosen by its association test p-value first,
+/// then the genotype threshold of the split by the criterion score
+/// Thresholds leaving fewer than the min leaf size on a side are skipped
+fn select_by_test(node_data: &NodeData, candidates: &[usize], params: &TreeParameters, alpha: f64, permutations: usize) -> Option<(usize, u8, f64)> {
+    let p_values: Vec<f64> = candidates.iter()
+        .map(|c| association_p_value(node_data, &node_data.genotypes(*c), params.outcome, permutations))
+        .collect();
+    let (i, p_min) = p_values.iter().enumerate().min_by(|a, b| a.1.partial_cmp(b.1).unwrap())?;
+    if p_min * candidates.len() as f64 > alpha {
+        return None
+    }
+    let g = &node_data.genotypes(candidates[i]);
+    let criterion = params.criterion.as_ref();
+    let min_leaf = params.stopping.min_leaf_size.unwrap_or(1);
+    let mut chosen: Option<(u8, f64)> = None;
+    for t in split_thresholds(g) {
+        let (n_left, n_right) = side_counts(g, t);
+        if n_left < min_leaf || n_right < min_leaf {
+            continue
+        }
+        let recoded = recode(g, t);
+        let score = criterion.score(&node_data.phenos, &node_data.events, &recoded.iter().collect::<Vec<&u8>>());
+        let better = match chosen {
+            None => true,
+            Some((_, s)) if criterion.minimize() => score < s,
+            Some((_, s)) => score > s
+        };
+        if better {
+            chosen = Some((t, score));
+        }
+    }
+    let (t, _) = chosen?;
+    let recoded = recode(g, t);
+    Some((candidates[i], t, shuffle_corrected_score(node_data, &recoded.iter().collect::<Vec<&u8>>(), criterion)))
+}
+
+/// Extremely randomized selection: only n_candidates random variants are scored, each at one
+/// random genotype threshold, and the best shuffle-corrected score among them is kept
+/// Variants without a threshold leaving the min leaf size on both sides are skipped
+fn select_at_random(node_data: &NodeData, candidates: &[usize], params: &TreeParameters, n_candidates: usize) -> Option<(usize, u8, f64)> {
+    let mut rng = thread_rng();
+    let criterion = params.criterion.as_ref();
+    let min_leaf = params.stopping.min_leaf_size.unwrap_or(1);
+    let mut drawn: Vec<(usize, u8)> = Vec::new();
+    let mut scores: Vec<f64> = Vec::new();
+    for c in candidates.choose_multiple(&mut rng, n_candidates.min(candidates.len())) {
+        let g = &node_data.genotypes(*c);
+        let t = match split_thresholds(g).choose(&mut rng) {
+            Some(t) => *t,
+            None => continue
+        };
+        let (n_left, n_right) = side_counts(g, t);
+        if n_left < min_leaf || n_right < min_leaf {
+            continue
+        }
+        let recoded = recode(g, t);
+        scores.push(shuffle_corrected_score(node_data, &recoded.iter().collect::<Vec<&u8>>(), criterion));
+        drawn.push((*c, t));
+    }
+    if scores.is_empty() {
+        return None
+    }
+    let best_score_index = if criterion.minimize() { utils::get_min_index(&scores) } else { utils::get_max_index(&scores) };
+    if !criterion.minimize() && scores[best_score_index].abs() == 0. {
+        return None
+    }
+    Some((drawn[best_score_index].0, drawn[best_score_index].1, scores[best_score_index]))
+}
+
+/// P-value of the association between a genotype vector and the outcome
+/// Chi-square test of the genotype by class table for binary and categorical outcomes,
+/// (n - 1) r^2 against chi-square(1) for continuous outcomes, log-rank (carriers against non-carriers) for survival
+/// With permutations > 0 the p-value is the fraction of permuted outcomes with at least the observed statistic
+fn association_p_value(node_data: &NodeData, g: &[&u8], outcome: matrix::Outcome, permutations: usize) -> f64 {
+    let (observed, df) = association_statistic(&node_data.phenos, &node_data.events, g, outcome);
+    if df == 0 || observed <= 0. {
+        return 1.
+    }
+    if permutations == 0 {
+        return statistics::chi_square_sf(observed, df as f64)
+    }
+    let mut rng = thread_rng();
+    let mut perm: Vec<usize> = (0..node_data.phenos.len()).collect();
+    let mut exceed: usize = 0;
+    for _ in 0..permutations {
+        perm.shuffle(&mut rng);
+        let p: Vec<&f64> = perm.iter().map(|i| node_data.phenos[*i]).collect();
+        let e: Vec<&f64> = if node_data.events.is_empty() { Vec::new() } else { perm.iter().map(|i| node_data.events[*i]).collect() };
+        if association_statistic(&p, &e, g, outcome).0 >= observed {
+            exceed += 1;
+        }
+    }
+    (1 + exceed) as f64 / (1 + permutations) as f64
+}
+
+/// Association test statistic and its degrees of freedom, only subjects with a called genotype are used
+fn association_statistic(p: &[&f64], e: &[&f64], g: &[&u8], outcome: matrix::Outcome) -> (f64, usize) {
+    let called: Vec<usize> = (0..g.len()).filter(|i| *g[*i] < matrix::MISSING_GENOTYPE).collect();
+    match outcome {
+        matrix::Outcome::Continuous => {
+            let x: Vec<f64> = called.iter().map(|i| *g[*i] as f64).collect();
+            let y: Vec<f64> = called.iter().map(|i| *p[*i]).collect();
+            ((called.len() as f64 - 1.).max(0.) * statistics::correlation_squared(&x, &y), 1)
+        },
+        matrix::Outcome::Survival => {
+            let recoded = recode(g, 0);
+            (calc_logrank(p, e, &recoded.iter().collect::<Vec<&u8>>()), 1)
+        },
+        _ => {
+            let mut table: Vec<Vec<f64>> = vec![Vec::new(); matrix::MISSING_GENOTYPE as usize];
+            for i in &called {
+                let (row, class) = (*g[*i] as usize, *p[*i] as usize);
+                if table[row].len() <= class {
+                    table[row].resize(class + 1, 0.);
+                }
+                table[row][class] += 1.;
+            }
+            statistics::chi_square_table(&table)
+        }
+    }
+}
+
+/// Distinct called genotypes that can serve as a split threshold (all but the largest)
+fn split_thresholds(g: &[&u8]) -> Vec<u8> {
+    let mut values: Vec<u8> = g.iter().map(|x| **x).filter(|x| *x < matrix::MISSING_GENOTYPE).collect();
+    values.sort_unstable();
+    values.dedup();
+    values.pop();
+    values
+}
+
+/// Number of subjects that go left (genotype up to t) and right (called genotype above t)
+fn side_counts(g: &[&u8], t: u8) -> (usize, usize) {
+    let n_left = g.iter().filter(|x| ***x <= t).count();
+    let n_right = g.iter().filter(|x| ***x > t && ***x < matrix::MISSING_GENOTYPE).count();
+    (n_left, n_right)
+}
+
+/// Recode genotypes to 0 (up to t) and 1 (above t), missing calls stay missing
+fn recode(g: &[&u8], t: u8) -> Vec<u8> {
+    g.iter().map(|x| match **x {
+        x if x >= matrix::MISSING_GENOTYPE => matrix::MISSING_GENOTYPE,
+        x if x <= t => 0,
+        _ => 1
+    }).collect()
+}
+
+/// Positions of the variants evaluated at a node
+/// All of the tree's variants unless a per-node fraction is given, then a fresh subset
+/// of at least one variant is drawn for every split; the n_forced last variants are always included
+fn candidate_variants(n_variants: usize, n_forced: usize, node_mtry: Option<f64>) -> Vec<usize> {
+    let n_drawn = n_variants - n_forced;
+    let mut candidates: Vec<usize> = match node_mtry {
+        None => (0..n_drawn).collect(),
+        Some(frac) => {
+            let n_draw = ((n_drawn as f64 * frac).round() as usize).clamp(1.min(n_drawn), n_drawn);
+            rand::seq::index::sample(&mut thread_rng(), n_drawn, n_draw).into_vec()
+        }
+    };
+    candidates.extend(n_drawn..n_variants);
+    candidates
+}
+
 /// Implementation of node data
 /// Methods to manage the data that is used in the growing tree
 impl<'a> NodeData<'a> {
 
-    fn split(&self, left_indices: &Vec<bool>, right_indices: &Vec<bool>) -> (Self, Self) {
-        let new_genos = self._split_genos(left_indices, right_indices);
-        let new_phenos = self._split_phenos(left_indices, right_indices);
-        let new_phenos_shuffle = self._split_phenos_shuffle(left_indices, right_indices);
+    /// Genotypes of the node's subjects for the variant at position c of the tree's variants
+    pub fn genotypes(&self, c: usize) -> Vec<&'a u8> {
+        self.genos.column(c, &self.rows)
+    }
+
+    fn split(&self, left_indices: &[bool], right_indices: &[bool]) -> (Self, Self) {
+        let new_rows = split_values(&self.rows, left_indices, right_indices);
+        let new_phenos = split_values(&self.phenos, left_indices, right_indices);
+        let new_phenos_shuffle = split_values(&self.phenos_shuffle, left_indices, right_indices);
+        let new_events = split_values(&self.events, left_indices, right_indices);
+        let new_events_shuffle = split_values(&self.events_shuffle, left_indices, right_indices);
         (
             NodeData {
-                genos: new_genos.0,
+                rows: new_rows.0,
                 phenos: new_phenos.0,
-                phenos_shuffle: new_phenos_shuffle.0
-
+                phenos_shuffle: new_phenos_shuffle.0,
+                events: new_events.0,
+                events_shuffle: new_events_shuffle.0,
+                genos: self.genos
             }, 
             NodeData {
-                genos: new_genos.1,
+                rows: new_rows.1,
                 phenos: new_phenos.1,
-                phenos_shuffle: new_phenos_shuffle.1
+                phenos_shuffle: new_phenos_shuffle.1,
+                events: new_events.1,
+                events_shuffle: new_events_shuffle.1,
+                genos: self.genos
             }
         )
 
     }
-
-    fn _split_genos(&self, left_indices: &Vec<bool>, right_indices: &Vec<bool>) -> (Vec<Vec<&'a u8>>, Vec<Vec<&'a u8>>) {
-        let mut left_genotypes: Vec<Vec<&u8>> = Vec::new();
-        let mut right_genotypes: Vec<Vec<&u8>> = Vec::new();
-        for g in &self.genos {
-            let mut ind: usize = 0;
-            let mut left_geno: Vec<&u8> = Vec::new();
-            let mut right_geno: Vec<&u8> = Vec::new();
-            for i in left_indices.iter().zip(right_indices.iter()) {
-                if *i.0 {
-                    left_geno.push(g[ind]);
-                }
-                if *i.1 {
-                    right_geno.push(g[ind]);
-                }
-                ind += 1;
-            }
-            left_genotypes.push(left_geno);
-            right_genotypes.push(right_geno)
-        };
-        return (left_genotypes, right_genotypes);
-    }
-    
-    fn _split_phenos(&self, left_indices: &Vec<bool>, right_indices: &Vec<bool>) -> (Vec<&'a f64>, Vec<&'a f64>)  {
-        let mut left_phenotypes: Vec<&f64> = Vec::new();
-        let mut right_phenotypes: Vec<&f64> = Vec::new();
-        let mut ind: usize = 0;
-        for i in left_indices.iter().zip(right_indices.iter()) {
-            if *i.0 {
-                left_phenotypes.push(self.phenos[ind]);
-            };
-            if *i.1 {
-                right_phenotypes.push(self.phenos[ind]);
-            }
-            ind += 1;
-        };
-        return (left_phenotypes, right_phenotypes);
-    }
-
-    fn _split_phenos_shuffle(&self, left_indices: &Vec<bool>, right_indices: &Vec<bool>) -> (Vec<&'a f64>, Vec<&'a f64>)  {
-        let mut left_phenotypes: Vec<&f64> = Vec::new();
-        let mut right_phenotypes: Vec<&f64> = Vec::new();
-        let mut ind: usize = 0;
-        for i in left_indices.iter().zip(right_indices.iter()) {
-            if *i.0 {
-                left_phenotypes.push(self.phenos_shuffle[ind]);
-            };
-            if *i.1 {
-                right_phenotypes.push(self.phenos_shuffle[ind]);
-            }
-            ind += 1;
-        };
-        return (left_phenotypes, right_phenotypes);
-    }
 }
 
-pub fn calc_sdr(p: &Vec<&f64>, g: &Vec<&u8>) -> f64 {
-    let top_sd = statistics::std_deviation(p)as f64;
+pub fn calc_sdr(p: &[&f64], g: &[&u8]) -> f64 {
+    let top_sd = statistics::std_deviation(p);
     let mut g0vec: Vec<&f64> = Vec::new();
     let mut g1vec: Vec<&f64> = Vec::new();
     for pg in p.iter().zip(g.iter()) {
@@ -286,18 +546,20 @@ pub fn calc_sdr(p: &Vec<&f64>, g: &Vec<&u8>) -> f64 {
         match **gi {
             0 => g0vec.push(pi),
             1 => g1vec.push(pi),
+            matrix::MISSING_GENOTYPE => (),
             _ => panic!("variable mismatch?, {}, {}", pi, gi),
         }
     }
-    let g0sd = statistics::std_deviation(&g0vec) as f64;
-    let g1sd = statistics::std_deviation(&g1vec) as f64;
-    if g0vec.len() == 0 || g1vec.len() == 0 {
+    let g0sd = statistics::std_deviation(&g0vec);
+    let g1sd = statistics::std_deviation(&g1vec);
+    if g0vec.is_empty() || g1vec.is_empty() {
         // prevents branching to the same or 100% correlated variant
         return 0.
     }
-    let sd_weighted = match g.len() {
+    let n_called = g0vec.len() + g1vec.len(); // missing genotypes are not counted
+    let sd_weighted = match n_called {
         0 => return 0.,
-        _ => {(g0sd * (g0vec.len() as f64 / g.len() as f64)) + (g1sd * (g1vec.len() as f64 / g.len() as f64 ))}
+        _ => {(g0sd * (g0vec.len() as f64 / n_called as f64)) + (g1sd * (g1vec.len() as f64 / n_called as f64 ))}
     };
     if sd_weighted > top_sd {
         return 0.
@@ -305,43 +567,332 @@ pub fn calc_sdr(p: &Vec<&f64>, g: &Vec<&u8>) -> f64 {
     top_sd - sd_weighted
 }
 
-pub fn calc_gini(p: &Vec<&f64>, g: &Vec<&u8>) -> f64 {
+/// Split a vector of per-subject values into the left and right nodes
+/// Empty vectors (e.g. events when the outcome is not survival) stay empty
+fn split_values<T: Copy>(values: &[T], left_indices: &[bool], right_indices: &[bool]) -> (Vec<T>, Vec<T>) {
+    let mut left: Vec<T> = Vec::new();
+    let mut right: Vec<T> = Vec::new();
+    for (v, (l, r)) in values.iter().zip(left_indices.iter().zip(right_indices.iter())) {
+        if *l {
+            left.push(*v);
+        }
+        if *r {
+            right.push(*v);
+        }
+    }
+    (left, right)
+}
+
+/// Prediction stored at a node
+/// Class proportions for binary and categorical outcomes, the mean for continuous outcomes,
+/// and the Nelson-Aalen cumulative hazard at each point of the time grid for survival outcomes
+fn node_value(node_data: &NodeData, params: &TreeParameters) -> Vec<f64> {
+    let p = &node_data.phenos;
+    if p.is_empty() {
+        return Vec::new()
+    }
+    match params.outcome {
+        matrix::Outcome::Continuous => vec![p.iter().copied().sum::<f64>() / p.len() as f64],
+        matrix::Outcome::Survival => cumulative_hazard(p, &node_data.events, &params.time_grid),
+        _ => {
+            let mut counts: Vec<f64> = vec![0.; params.n_classes];
+            for pi in p {
+                counts[**pi as usize] += 1.;
+            }
+            counts.iter().map(|c| c / p.len() as f64).collect()
+        }
+    }
+}
+
+/// Nelson-Aalen estimate of the cumulative hazard at each time point in the grid
+fn cumulative_hazard(times: &[&f64], events: &[&f64], grid: &[f64]) -> Vec<f64> {
+    let mut te: Vec<(f64, f64)> = times.iter().zip(events.iter()).map(|(t, e)| (**t, **e)).collect();
+    te.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut hazard: Vec<f64> = Vec::with_capacity(grid.len());
+    let mut h: f64 = 0.;
+    let mut i: usize = 0;
+    for t in grid {
+        while i < te.len() && te[i].0 <= *t {
+            // all subjects with this time leave the risk set together
+            let time = te[i].0;
+            let at_risk = (te.len() - i) as f64;
+            let mut deaths: f64 = 0.;
+            while i < te.len() && te[i].0 == time {
+                deaths += te[i].1;
+                i += 1;
+            }
+            h += deaths / at_risk;
+        }
+        hazard.push(h);
+    }
+    hazard
+}
+
+/// Log-rank test statistic (chi-square, 1 df) comparing survival of genotype 0 against genotype 1
+/// Returns 0 when either group is empty or there are no events
+pub fn calc_logrank(t: &[&f64], e: &[&f64], g: &[&u8]) -> f64 {
+    let mut obs: Vec<(f64, f64, bool)> = Vec::new(); // time, event, in genotype 1 group
+    for ((ti, ei), gi) in t.iter().zip(e.iter()).zip(g.iter()) {
+        match **gi {
+            0 => obs.push((**ti, **ei, false)),
+            1 => obs.push((**ti, **ei, true)),
+            matrix::MISSING_GENOTYPE => (),
+            _ => panic!("variable mismatch?, {}, {}", ti, gi),
+        }
+    }
+    let mut at_risk_1 = obs.iter().filter(|o| o.2).count() as f64;
+    if at_risk_1 == 0. || at_risk_1 == obs.len() as f64 {
+        return 0.
+    }
+    obs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut at_risk = obs.len() as f64;
+    let mut o_minus_e: f64 = 0.;
+    let mut var: f64 = 0.;
+    let mut i: usize = 0;
+    while i < obs.len() {
+        let time = obs[i].0;
+        let (mut d, mut d1, mut n_out, mut n1_out) = (0., 0., 0., 0.);
+        while i < obs.len() && obs[i].0 == time {
+            d += obs[i].1;
+            n_out += 1.;
+            if obs[i].2 {
+                d1 += obs[i].1;
+                n1_out += 1.;
+            }
+            i += 1;
+        }
+        if d > 0. {
+            let frac_1 = at_risk_1 / at_risk;
+            o_minus_e += d1 - d * frac_1;
+            if at_risk > 1. {
+                var += d * frac_1 * (1. - frac_1) * (at_risk - d) / (at_risk - 1.);
+            }
+        }
+        at_risk -= n_out;
+        at_risk_1 -= n1_out;
+    }
+    if var <= 0. {
+        return 0.
+    }
+    o_minus_e * o_minus_e / var
+}
+
+pub fn calc_gini(p: &[&f64], g: &[&u8]) -> f64 {
     /* 
     vector of genotypes (0,1,2) (g)
-    vector of phenotypes (0,1) (p)
-
-    geno 0 = pheno 0
-    geno 0 = pheno 1
-    ---
-    get pheno where p = 0
-    get pheno where p = 1
+    vector of phenotypes as class labels (0,1 for binary, 0..k-1 for categorical) (p)
 
+    count each class within genotype 0 and genotype 1,
+    then weight the gini impurity of each genotype group by its size
     */
 
-    let mut p0g0: f64 = 0.;
-    let mut p1g0: f64 = 0.;
-    let mut p0g1: f64 = 0.;
-    let mut p1g1: f64 = 0.;
+    let mut g0: Vec<f64> = Vec::new(); // class counts where genotype is 0
+    let mut g1: Vec<f64> = Vec::new(); // class counts where genotype is 1
 
     for pg in p.iter().zip(g.iter()) {
         let (pi, gi) = pg;
-        match (**pi as u64, *gi) {
-            (0, 0) => p0g0 += 1.,
-            (1, 0) => p1g0 += 1.,
-            (0, 1) => p0g1 += 1.,
-            (1, 1) => p1g1 += 1.,
+        let counts = match **gi {
+            0 => &mut g0,
+            1 => &mut g1,
+            matrix::MISSING_GENOTYPE => continue,
             _ => panic!("variable mismatch?, {}, {}", pi, gi),
+        };
+        let class = **pi as usize;
+        if counts.len() <= class {
+            counts.resize(class + 1, 0.);
         }
+        counts[class] += 1.;
     }
 
-    let p_inst: f64 = p.len() as f64;
+    let n0: f64 = g0.iter().sum();
+    let n1: f64 = g1.iter().sum();
+    if n0 == 0. || n1 == 0. {
+        // one of the genotype groups is empty, so there is no split
+        return 1.
+    }
+    let p_inst: f64 = n0 + n1; // missing genotypes are not counted
 
-    let g0_g = 1. - ((p0g0 / (p0g0+p1g0)).powi(2) + (p1g0 / (p0g0+p1g0)).powi(2));
-    let g1_g = 1. - ((p0g1 / (p0g1+p1g1)).powi(2) + (p1g1 / (p0g1+p1g1)).powi(2));
+    let g0_g = 1. - g0.iter().map(|c| (c / n0).powi(2)).sum::<f64>();
+    let g1_g = 1. - g1.iter().map(|c| (c / n1).powi(2)).sum::<f64>();
 
-    let gi = (((p0g0+p1g0)/p_inst) * g0_g) + (((p0g1+p1g1)/p_inst) * g1_g);
-    if f64::is_nan(gi) {
-        return 1.
-    };
-    return gi
+    (n0 / p_inst) * g0_g + (n1 / p_inst) * g1_g
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::test_utils;
+
+    fn refs<T>(x: &[T]) -> Vec<&T> {
+        x.iter().collect()
+    }
+
+    fn params(outcome: matrix::Outcome, selection: SplitSelection, stopping: StoppingRules, max_depth: i32) -> TreeParameters {
+        TreeParameters {
+            max_depth,
+            outcome,
+            n_classes: 2,
+            time_grid: Vec::new(),
+            node_mtry: None,
+            n_forced: 0,
+            stopping,
+            criterion: criterion::Criterion::default_for(outcome).make(2),
+            selection
+        }
+    }
+
+    fn no_stopping() -> StoppingRules {
+        StoppingRules { min_node_size: None, min_leaf_size: None, min_impurity_decrease: None, max_leaves: None }
+    }
+
+    /// Binary outcome of 40 subjects: variant 0 is the outcome, variant 1 is independent of it and
+    /// variant 2 is the outcome with 12 subjects flipped
+    fn association_rows() -> Vec<(f64, Vec<u8>)> {
+        (0..40).map(|i| {
+            let y = (i % 2) as u8;
+            (y as f64, vec![y, ((i / 2) % 2) as u8, if i < 12 { 1 - y } else { y }])
+        }).collect()
+    }
+
+    /// Survival matrix from (time, event, genotypes) rows, the event is the column after the time
+    fn survival_matrix(rows: &[(f64, u8, Vec<u8>)]) -> matrix::GenoMatrix {
+        let rows: Vec<(f64, Vec<u8>)> = rows.iter().map(|(t, e, g)| (*t, [vec![*e], g.clone()].concat())).collect();
+        test_utils::matrix(&rows, matrix::Outcome::Survival)
+    }
+
+    #[test]
+    fn sdr_counts_negative_phenotypes() {
+        // population sd sqrt(5) at the parent, 1 in each child; shifting the phenotypes changes nothing
+        let g: Vec<u8> = vec![0, 0, 1, 1];
+        let p = [-3., -1., 1., 3.];
+        let shifted: Vec<f64> = p.iter().map(|x| x + 10.).collect();
+        let sdr = calc_sdr(&refs(&p), &refs(&g));
+        assert!((sdr - (5f64.sqrt() - 1.)).abs() < 1e-12);
+        assert!((sdr - calc_sdr(&refs(&shifted), &refs(&g))).abs() < 1e-12);
+    }
+
+    #[test]
+    fn logrank_two_groups() {
+        // tied deaths at time 4 (one per group), a death and a censoring at time 2
+        let t = [1., 2., 2., 4., 4., 5., 6., 7., 8.];
+        let e = [1., 1., 0., 1., 1., 0., 1., 1., 1.];
+        let g: [u8; 9] = [0, 0, 1, 0, 1, 1, 1, 0, 1];
+        // genotype 1: 3 deaths observed, expected 5/9 + 5/8 + 2 * 4/6 + 2/3 + 1/2 + 1
+        let o_minus_e = 3. - (5. / 9. + 5. / 8. + 8. / 6. + 2. / 3. + 1. / 2. + 1.);
+        let var = 20. / 81. + 15. / 64. + 2. * (4. / 6.) * (2. / 6.) * 4. / 5. + 2. / 9. + 1. / 4.;
+        assert!((calc_logrank(&refs(&t), &refs(&e), &refs(&g)) - o_minus_e * o_minus_e / var).abs() < 1e-12);
+        // only censored subjects, or a single group: no statistic
+        let censored = [0.; 9];
+        assert_eq!(calc_logrank(&refs(&t), &refs(&censored), &refs(&g)), 0.);
+        assert_eq!(calc_logrank(&refs(&t), &refs(&e), &refs(&[1u8; 9])), 0.);
+    }
+
+    #[test]
+    fn nelson_aalen_hazard() {
+        let t = [1., 2., 2., 3., 5.];
+        let e = [1., 1., 0., 1., 0.];
+        let grid = [0., 1., 2., 2.5, 3., 5., 6.];
+        // 1/5 at time 1, 1/4 at time 2 (both time 2 subjects leave together), 1/2 at time 3
+        let expected = [0., 0.2, 0.45, 0.45, 0.95, 0.95, 0.95];
+        let h = cumulative_hazard(&refs(&t), &refs(&e), &grid);
+        assert!(h.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-12));
+        assert_eq!(cumulative_hazard(&refs(&t), &refs(&[0.; 5]), &grid), vec![0.; 7]);
+    }
+
+    #[test]
+    fn survival_pairs_permuted_together() {
+        // the event is set exactly for even times, which a shuffle must keep
+        let rows: Vec<(f64, u8, Vec<u8>)> = (0..30usize).map(|i| (i as f64, i.is_multiple_of(2) as u8, vec![0])).collect();
+        let gm = survival_matrix(&rows);
+        let slice = matrix::GenoMatrixSlice { subj_ids: (0..30).collect(), genotype_ids: vec![0] };
+        let genos = gm.slice_genotypes(&slice, true);
+        let data = gm.get_slice_data(&slice, &genos);
+        assert_ne!(data.phenos, data.phenos_shuffle);
+        for (t, e) in data.phenos_shuffle.iter().zip(data.events_shuffle.iter()) {
+            assert_eq!(**e, (**t as usize).is_multiple_of(2) as u8 as f64);
+        }
+    }
+
+    #[test]
+    fn conditional_inference_selection() {
+        let gm = test_utils::matrix(&association_rows(), matrix::Outcome::Binary);
+        let slice = matrix::GenoMatrixSlice { subj_ids: (0..40).collect(), genotype_ids: vec![0, 1, 2] };
+        let genos = gm.slice_genotypes(&slice, true);
+        let data = gm.get_slice_data(&slice, &genos);
+        let ci = params(matrix::Outcome::Binary, SplitSelection::ConditionalInference { alpha: 0.05, permutations: 0 }, no_stopping(), 3);
+        // a perfect 20/20 split has chi-square 40, the independent variant has chi-square 0
+        assert_eq!(association_p_value(&data, &data.genotypes(0), matrix::Outcome::Binary, 0), statistics::chi_square_sf(40., 1.));
+        assert_eq!(association_p_value(&data, &data.genotypes(1), matrix::Outcome::Binary, 0), 1.);
+        // no permuted outcome reaches a perfect split
+        assert_eq!(association_p_value(&data, &data.genotypes(0), matrix::Outcome::Binary, 99), 0.01);
+        assert_eq!(association_p_value(&data, &data.genotypes(1), matrix::Outcome::Binary, 99), 1.);
+        // the null node stops, the strong association splits at threshold 0
+        assert!(select_by_test(&data, &[1], &ci, 0.05, 0).is_none());
+        assert_eq!(select_by_test(&data, &[1, 0], &ci, 0.05, 0).map(|s| (s.0, s.1)), Some((0, 0)));
+        // Bonferroni: the weaker association passes alone but not as the best of two candidates
+        let p = association_p_value(&data, &data.genotypes(2), matrix::Outcome::Binary, 0);
+        assert!(select_by_test(&data, &[2], &ci, 1.5 * p, 0).is_some());
+        assert!(select_by_test(&data, &[2, 1], &ci, 1.5 * p, 0).is_none());
+    }
+
+    /// Continuous outcome i for subjects 0..16, variant k is bit k of i, so any node can be split further
+    fn grow_bits(stopping: StoppingRules) -> Node {
+        let rows: Vec<(f64, Vec<u8>)> = (0..16).map(|i| (i as f64, (0..4).map(|k| ((i >> k) & 1) as u8).collect())).collect();
+        let gm = test_utils::matrix(&rows, matrix::Outcome::Continuous);
+        let slice = matrix::GenoMatrixSlice { subj_ids: (0..16).collect(), genotype_ids: (0..4).collect() };
+        let genos = gm.slice_genotypes(&slice, true);
+        let params = params(matrix::Outcome::Continuous, SplitSelection::Impurity, stopping, 10);
+        Node::grow(gm.get_slice_data(&slice, &genos), &slice, &params)
+    }
+
+    /// The nodes of a tree that were split
+    fn split_nodes(n: &Node) -> Vec<&Node> {
+        if n.is_empty {
+            return Vec::new()
+        }
+        let mut nodes = vec![n];
+        for child in [&n.left, &n.right].into_iter().flatten() {
+            nodes.extend(split_nodes(child));
+        }
+        nodes
+    }
+
+    #[test]
+    fn min_node_size() {
+        let tree = grow_bits(StoppingRules { min_node_size: Some(17), ..no_stopping() });
+        assert!(tree.is_empty && tree.node_n == 16);
+        let tree = grow_bits(StoppingRules { min_node_size: Some(8), ..no_stopping() });
+        let splits = split_nodes(&tree);
+        assert!(!splits.is_empty() && splits.iter().all(|n| n.node_n >= 8));
+    }
+
+    #[test]
+    fn min_leaf_size() {
+        let tree = grow_bits(StoppingRules { min_leaf_size: Some(3), ..no_stopping() });
+        let splits = split_nodes(&tree);
+        assert!(!splits.is_empty());
+        for n in splits {
+            assert!(n.left.as_ref().unwrap().node_n >= 3 && n.right.as_ref().unwrap().node_n >= 3);
+        }
+    }
+
+    #[test]
+    fn max_leaves_depth_first() {
+        let tree = grow_bits(StoppingRules { max_leaves: Some(3), ..no_stopping() });
+        // the root and then its left child split, which uses up the leaves before the right child
+        assert_eq!(split_nodes(&tree).len(), 2);
+        let left = tree.left.as_ref().unwrap();
+        assert!(!left.is_empty && left.left.as_ref().unwrap().is_empty && left.right.as_ref().unwrap().is_empty);
+        assert!(tree.right.as_ref().unwrap().is_empty);
+    }
+
+    #[test]
+    fn min_impurity_decrease() {
+        // the root split on the highest bit reduces the sd by more than 1.5, the decrease is the sdr itself
+        let tree = grow_bits(StoppingRules { min_impurity_decrease: Some(1.5), ..no_stopping() });
+        assert!(!tree.is_empty);
+        assert!(split_nodes(&tree).iter().all(|n| n.score >= 1.5));
+        let tree = grow_bits(StoppingRules { min_impurity_decrease: Some(1e9), ..no_stopping() });
+        assert!(tree.is_empty);
+    }
 }
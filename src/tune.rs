@@ -0,0 +1,362 @@
+// Copyright 2020 Solomon M. Adams, PharmD, PhD
+// Licensed under the MIT license
+
+//! Hyperparameter search
+//! Each configuration overrides some settings of the full run and is scored by the OOB error of its
+//! final forest or by cross-validation, the configurations are evaluated in parallel on the thread pool
+
+use crate::criterion;
+use crate::cv;
+use crate::forest;
+use crate::matrix;
+use crate::tree;
+
+use rand::{Rng, thread_rng};
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
+
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Settings that can be searched, named like their command line options
+const SETTINGS: [&str; 20] = [
+    "n-tree", "mtry", "max-depth", "subj-fraction",
+    "n-tree-2", "mtry-2", "max-depth-2", "subj-fraction-2",
+    "n-iter", "z-keep",
+    "min-node-size", "min-leaf-size", "min-impurity-decrease", "max-leaves",
+    "min-node-size-2", "min-leaf-size-2", "min-impurity-decrease-2", "max-leaves-2",
+    "bootstrap", "mtry-mode"
+];
+
+/// Settings taking whole numbers, values drawn from a range are rounded
+const INTEGER_SETTINGS: [&str; 11] = [
+    "n-tree", "max-depth", "n-tree-2", "max-depth-2", "n-iter",
+    "min-node-size", "min-leaf-size", "max-leaves", "min-node-size-2", "min-leaf-size-2", "max-leaves-2"
+];
+
+/// Values tried for one setting: a list of values, or a numeric range sampled uniformly (random search only)
+pub enum Values {
+    List(Vec<String>),
+    Range(f64, f64)
+}
+
+pub struct Param {
+    pub name: String,
+    pub values: Values
+}
+
+/// One configuration, a value for every searched setting
+pub type Config = Vec<(String, String)>;
+
+pub enum Search {
+    Grid,
+    Random(usize)
+}
+
+/// Metric ranking the configurations
+/// OobError is the OOB error of the final forest, the others are cross-validation means (see cv::evaluate)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Metric {
+    OobError,
+    Auc,
+    Accuracy,
+    R2,
+    Mse,
+    CIndex
+}
+
+/// Score of a configuration
+pub struct TuneResult {
+    pub config: Config,
+    pub settings: cv::RunSettings,
+    pub score: f64
+}
+
+impl Param {
+
+    /// Parse name=v1,v2,... or name=min:max
+    /// Also accepts split-criterion, which is searched like the other settings
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (name, values) = spec.split_once('=').ok_or(format!("{} is not name=values", spec))?;
+        if !SETTINGS.contains(&name) && name != "split-criterion" {
+            return Err(format!("{} cannot be tuned", name))
+        }
+        let range: Option<(f64, f64)> = values.split_once(':').and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)));
+        let values = match range {
+            Some((a, b)) if a <= b => Values::Range(a, b),
+            Some(_) => return Err(format!("empty range for {}", name)),
+            None => Values::List(values.split(',').map(String::from).collect())
+        };
+        Ok(Param { name: name.to_string(), values })
+    }
+}
+
+impl Metric {
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "oob-error" => Ok(Metric::OobError),
+            "auc" => Ok(Metric::Auc),
+            "accuracy" => Ok(Metric::Accuracy),
+            "r2" => Ok(Metric::R2),
+            "mse" => Ok(Metric::Mse),
+            "c-index" => Ok(Metric::CIndex),
+            _ => Err(format!("unknown tuning metric {}", name))
+        }
+    }
+
+    /// Name of the metric in the cross-validation results
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::OobError => "oob_error",
+            Metric::Auc => "auc",
+            Metric::Accuracy => "accuracy",
+            Metric::R2 => "r2",
+            Metric::Mse => "mse",
+            Metric::CIndex => "c_index"
+        }
+    }
+
+    /// Whether lower values are better
+    pub fn minimize(&self) -> bool {
+        matches!(self, Metric::OobError | Metric::Mse)
+    }
+
+    /// Check that cross-validation reports the metric for the outcome type
+    pub fn check(&self, outcome: matrix::Outcome) -> Result<(), String> {
+        match (self, outcome) {
+            (Metric::OobError, _) => Ok(()),
+            (Metric::Auc, matrix::Outcome::Binary) => Ok(()),
+            (Metric::Accuracy, matrix::Outcome::Binary | matrix::Outcome::Categorical) => Ok(()),
+            (Metric::R2 | Metric::Mse, matrix::Outcome::Continuous) => Ok(()),
+            (Metric::CIndex, matrix::Outcome::Survival) => Ok(()),
+            _ => Err(format!("metric {} is not reported for a {:?} outcome", self.name(), outcome))
+        }
+    }
+}
+
+/// The configurations to evaluate
+/// Grid search takes every combination of the listed values, random search draws n configurations
+pub fn configurations(params: &[Param], search: &Search) -> Result<Vec<Config>, String> {
+    match search {
+        Search::Grid => {
+            let mut configs: Vec<Config> = vec![Vec::new()];
+            for p in params {
+                let values = match &p.values {
+                    Values::List(v) => v,
+                    Values::Range(_, _) => return Err(format!("grid search needs a list of values for {}", p.name))
+                };
+                configs = configs.iter()
+                    .flat_map(|c| values.iter().map(move |v| {
+                        let mut next = c.clone();
+                        next.push((p.name.clone(), v.clone()));
+                        next
+                    }))
+                    .collect();
+            }
+            Ok(configs)
+        },
+        Search::Random(n) => {
+            let mut rng = thread_rng();
+            Ok((0..*n).map(|_| params.iter().map(|p| {
+                let value = match &p.values {
+                    Values::List(v) => v.choose(&mut rng).unwrap().clone(),
+                    Values::Range(a, b) if INTEGER_SETTINGS.contains(&p.name.as_str()) => {
+                        rng.gen_range(a.round() as i64..=b.round() as i64).to_string()
+                    },
+                    Values::Range(a, b) => (a + (b - a) * rng.gen::<f64>()).to_string()
+                };
+                (p.name.clone(), value)
+            }).collect()).collect())
+        }
+    }
+}
+
+/// Apply a configuration to the settings of the base run
+pub fn apply(base: &cv::RunSettings, config: &Config) -> Result<cv::RunSettings, String> {
+    let mut s = *base;
+    for (name, value) in config {
+        set(&mut s, name, value)?;
+    }
+    Ok(s)
+}
+
+fn set(s: &mut cv::RunSettings, name: &str, value: &str) -> Result<(), String> {
+    let int = || value.parse::<usize>().map_err(|_| format!("{} needs a whole number, got {}", name, value));
+    let float = || value.parse::<f64>().map_err(|_| format!("{} needs a number, got {}", name, value));
+    // same ranges as the command line options (see check_args)
+    let positive = || match int()? {
+        0 => Err(format!("{} must be greater than 0, got {}", name, value)),
+        v => Ok(v as i32)
+    };
+    let fraction = || match float()? {
+        v if v > 0. && v <= 1. => Ok(v),
+        v => Err(format!("{} must be in (0, 1], got {}", name, v))
+    };
+    match name {
+        "n-tree" => s.hp.n_tree = positive()?,
+        "mtry" => s.hp.mtry = fraction()?,
+        "max-depth" => s.hp.max_depth = positive()?,
+        "subj-fraction" => s.hp.subj_fraction = fraction()?,
+        "n-tree-2" => s.hp2.n_tree = positive()?,
+        "mtry-2" => s.hp2.mtry = fraction()?,
+        "max-depth-2" => s.hp2.max_depth = positive()?,
+        "subj-fraction-2" => s.hp2.subj_fraction = fraction()?,
+        "n-iter" => s.n_iter = int()?,
+        "z-keep" => s.z_keep = match float()? {
+            v if v >= 0. => v,
+            v => return Err(format!("z-keep must be at least 0, got {}", v))
+        },
+        "min-node-size" => s.hp.stopping.min_node_size = Some(int()?),
+        "min-leaf-size" => s.hp.stopping.min_leaf_size = Some(int()?),
+        "min-impurity-decrease" => s.hp.stopping.min_impurity_decrease = Some(float()?),
+        "max-leaves" => s.hp.stopping.max_leaves = Some(int()?),
+        "min-node-size-2" => s.hp2.stopping.min_node_size = Some(int()?),
+        "min-leaf-size-2" => s.hp2.stopping.min_leaf_size = Some(int()?),
+        "min-impurity-decrease-2" => s.hp2.stopping.min_impurity_decrease = Some(float()?),
+        "max-leaves-2" => s.hp2.stopping.max_leaves = Some(int()?),
+        "bootstrap" => {
+            let b = value.parse::<bool>().map_err(|_| format!("bootstrap needs true or false, got {}", value))?;
+            s.hp.bootstrap = b;
+            s.hp2.bootstrap = b;
+        },
+        "mtry-mode" => {
+            let mode = match value {
+                "per-tree" => forest::MtryMode::PerTree,
+                "per-node" => forest::MtryMode::PerNode,
+                _ => return Err(format!("unknown mtry mode {}", value))
+            };
+            s.hp.mtry_mode = mode;
+            s.hp2.mtry_mode = mode;
+        },
+        "split-criterion" => {
+            let c = criterion::Criterion::parse(value)?;
+            c.check(s.hp.outcome)?;
+            s.hp.criterion = c;
+            s.hp2.criterion = c;
+        },
+        _ => return Err(format!("{} cannot be tuned", name))
+    }
+    Ok(())
+}
+
+/// Score every configuration, each on its own copy of the matrix, and rank them best first
+/// Configurations without a score (NaN) are ranked last
+pub fn tune(gm: &matrix::GenoMatrix, candidates: Vec<(Config, cv::RunSettings)>, metric: Metric, folds: &[Vec<usize>]) -> Result<Vec<TuneResult>, io::Error> {
+    let mut results: Vec<TuneResult> = candidates.into_par_iter().map(|(config, settings)| {
+        let mut data = gm.clone();
+        let score = match metric {
+            Metric::OobError => cv::fit(&mut data, &settings, true)?.oob_error(&data).unwrap_or(f64::NAN),
+            _ => {
+                let folds = cv::cross_validate(&mut data, &settings, folds)?;
+                cv::mean_metrics(&folds).iter().find(|(name, _)| *name == metric.name()).map_or(f64::NAN, |(_, v)| *v)
+            }
+        };
+        Ok(TuneResult { config, settings, score })
+    }).collect::<Result<Vec<TuneResult>, io::Error>>()?;
+    results.sort_by(|a, b| {
+        let order = if metric.minimize() { a.score.partial_cmp(&b.score) } else { b.score.partial_cmp(&a.score) };
+        a.score.is_nan().cmp(&b.score.is_nan()).then(order.unwrap_or(std::cmp::Ordering::Equal))
+    });
+    Ok(results)
+}
+
+/// print the ranked configurations to stdout
+pub fn print_results(results: &[TuneResult], metric: Metric) {
+    println!("#TUNING");
+    let names: Vec<&str> = results.first().map_or(Vec::new(), |r| r.config.iter().map(|(n, _)| n.as_str()).collect());
+    println!("#rank\t{}\t{}", names.join("\t"), metric.name());
+    for (i, r) in results.iter().enumerate() {
+        let values: Vec<&str> = r.config.iter().map(|(_, v)| v.as_str()).collect();
+        println!("{}\t{}\t{:?}", i + 1, values.join("\t"), r.score);
+    }
+}
+
+/// Command line options reproducing the settings of a run
+/// Written as --option=value so negative values (e.g. z-keep) parse
+pub fn settings_args(s: &cv::RunSettings) -> String {
+    let mut opts: Vec<String> = vec![
+        format!("--n-tree={}", s.hp.n_tree),
+        format!("--mtry={}", s.hp.mtry),
+        format!("--max-depth={}", s.hp.max_depth),
+        format!("--subj-fraction={}", s.hp.subj_fraction),
+        format!("--n-tree-2={}", s.hp2.n_tree),
+        format!("--mtry-2={}", s.hp2.mtry),
+        format!("--max-depth-2={}", s.hp2.max_depth),
+        format!("--subj-fraction-2={}", s.hp2.subj_fraction),
+        format!("--n-iter={}", s.n_iter),
+        format!("--z-keep={}", s.z_keep)
+    ];
+    for (suffix, stop) in [("", &s.hp.stopping), ("-2", &s.hp2.stopping)] {
+        if let Some(v) = stop.min_node_size {
+            opts.push(format!("--min-node-size{}={}", suffix, v));
+        }
+        if let Some(v) = stop.min_leaf_size {
+            opts.push(format!("--min-leaf-size{}={}", suffix, v));
+        }
+        if let Some(v) = stop.min_impurity_decrease {
+            opts.push(format!("--min-impurity-decrease{}={}", suffix, v));
+        }
+        if let Some(v) = stop.max_leaves {
+            opts.push(format!("--max-leaves{}={}", suffix, v));
+        }
+    }
+    if s.hp.bootstrap {
+        opts.push(String::from("--bootstrap"));
+    }
+    opts.push(format!("--mtry-mode={}", match s.hp.mtry_mode {
+        forest::MtryMode::PerTree => "per-tree",
+        forest::MtryMode::PerNode => "per-node"
+    }));
+    opts.push(format!("--split-criterion={}", s.hp.criterion.name()));
+    opts.push(match s.hp.selection {
+        tree::SplitSelection::Impurity => String::from("--split-selection=impurity"),
+        tree::SplitSelection::ConditionalInference { alpha, permutations } => {
+            format!("--split-selection=conditional --ci-alpha={} --ci-permutations={}", alpha, permutations)
+        },
+        tree::SplitSelection::ExtraTrees { n_candidates } => {
+            format!("--split-selection=extra-trees --extra-candidates={}", n_candidates)
+        }
+    });
+    opts.join(" ")
+}
+
+/// Write the options of the best configuration on one line, ready to be added to the command line
+pub fn write_best(path: &str, s: &cv::RunSettings) -> io::Result<()> {
+    let mut w = File::create(path)?;
+    writeln!(w, "{}", settings_args(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> cv::RunSettings {
+        let hp = forest::HyperParameters {
+            n_tree: 10,
+            mtry: 0.5,
+            max_depth: 2,
+            subj_fraction: 0.5,
+            outcome: matrix::Outcome::Binary,
+            bootstrap: false,
+            mtry_mode: forest::MtryMode::PerTree,
+            criterion: criterion::Criterion::Gini,
+            selection: tree::SplitSelection::Impurity,
+            stopping: tree::StoppingRules { min_node_size: None, min_leaf_size: None, min_impurity_decrease: None, max_leaves: None }
+        };
+        cv::RunSettings { hp, hp2: hp, z_keep: 1., n_iter: 1 }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let config = |name: &str, value: &str| vec![(name.to_string(), value.to_string())];
+        for (name, value) in [("mtry", "0"), ("mtry", "1.5"), ("subj-fraction", "-0.1"), ("mtry-2", "2"), ("subj-fraction-2", "0"),
+                              ("n-tree", "0"), ("max-depth", "0"), ("n-tree-2", "0"), ("max-depth-2", "0"), ("z-keep", "-1")] {
+            assert!(apply(&base(), &config(name, value)).is_err(), "{}={}", name, value);
+        }
+        let s = apply(&base(), &config("mtry", "1")).unwrap();
+        assert_eq!(s.hp.mtry, 1.);
+        let s = apply(&base(), &config("z-keep", "0")).unwrap();
+        assert_eq!(s.z_keep, 0.);
+    }
+}
@@ -27,50 +27,57 @@ pub fn sum_bool_vec(v: &Vec<bool>) -> i32 {
             s += 1;
         };
     }
-    return s;
+    s
 }
 
-/// Take vector of floats, return the index of the minumum
-pub fn get_min_index(vals: &Vec<f64>) -> usize {
+/// Take vector of floats, return the index of the minumum absolute value
+pub fn get_min_index(vals: &[f64]) -> usize {
     let mut min_val: f64 = 1.;
     let mut min_i: usize = 0;
-    let mut i: usize = 0;
-    for g in vals {
+    for (i, g) in vals.iter().enumerate() {
         let abs_val = g.abs(); // get absolute value since they might be negative
         if abs_val < min_val {
-            min_val = *g;
+            min_val = abs_val;
             min_i = i;
         };
-        i += 1;
     }
-    return min_i
+    min_i
 }
 
-/// Take vector of floats, return the index of the maximum
-pub fn get_max_index(vals: &Vec<f64>) -> usize {
+/// Take vector of floats, return the index of the maximum absolute value
+pub fn get_max_index(vals: &[f64]) -> usize {
     let mut max_val: f64 = 0.;
     let mut max_i: usize = 0;
-    let mut i: usize = 0;
-    for g in vals {
+    for (i, g) in vals.iter().enumerate() {
         let abs_val = g.abs(); // get absolute value since they might be negative
         if abs_val > max_val {
-            max_val = *g;
+            max_val = abs_val;
             max_i = i;
         };
-        i += 1;
     }
-    return max_i
+    max_i
+}
+
+/// Index of the largest value (the first one on ties), for picking a predicted class
+pub fn argmax(vals: &[f64]) -> usize {
+    let mut max_i: usize = 0;
+    for (i, v) in vals.iter().enumerate() {
+        if *v > vals[max_i] {
+            max_i = i;
+        }
+    }
+    max_i
 }
 
 /// Get the size of a reader object
-/// In number of lines
-pub fn get_mat_size(rdr: &mut csv::Reader<File>) -> Shape {
+/// In number of lines, and number of columns after the lead_cols leading (id and outcome) columns
+pub fn get_mat_size(rdr: &mut csv::Reader<File>, lead_cols: usize) -> Shape {
     let mut ncols: usize = 0;
     let mut nrows: usize = 0;
     for result in rdr.byte_records() {
             let record = result.unwrap();
             if ncols == 0 {
-                ncols = &record.len() - 2;
+                ncols = record.len() - lead_cols;
             };
             nrows += 1;
     }
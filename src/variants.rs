@@ -6,17 +6,134 @@
 
 pub struct Variant {
     pub id: String,
+    pub chrom: Option<String>,
+    pub pos: Option<u64>,
+    pub ref_allele: Option<String>,
+    pub alt_allele: Option<String>,
+    pub af: Option<f64>, // alternate allele frequency
+    pub gene: Option<String>,
     pub max_importance: f64
 }
 
+/// Column names matching Variant::describe
+pub const DESCRIBE_HEADER: &str = "chrom\tpos\tref\talt\taf\tgene";
+
 impl Variant {
     pub fn new(id: String) -> Self {
-        return Variant {
-            id: id, 
+        Variant {
+            id,
+            chrom: None,
+            pos: None,
+            ref_allele: None,
+            alt_allele: None,
+            af: None,
+            gene: None,
             max_importance: 0.
         }
     }
+
+    /// Build a variant from the fields of a variant table row (after the index column)
+    /// id, chromosome, position, ref allele, alt allele, allele frequency, gene
+    /// Everything after the id is optional, and empty, NA or . fields are treated as missing
+    pub fn from_fields(fields: &[&str]) -> Self {
+        let field = |i: usize| fields.get(i).map(|f| f.trim()).filter(|f| !f.is_empty() && *f != "NA" && *f != ".");
+        let mut v = Variant::new(String::from(fields[0]));
+        v.chrom = field(1).map(normalize_chrom);
+        v.pos = field(2).and_then(|p| p.parse::<u64>().ok());
+        v.ref_allele = field(3).map(String::from);
+        v.alt_allele = field(4).map(String::from);
+        v.af = field(5).and_then(|f| f.parse::<f64>().ok());
+        v.gene = field(6).map(String::from);
+        v
+    }
+
+    /// The fields written back out for a variant, in variant table order
+    pub fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.chrom.clone().unwrap_or_default(),
+            self.pos.map(|p| p.to_string()).unwrap_or_default(),
+            self.ref_allele.clone().unwrap_or_default(),
+            self.alt_allele.clone().unwrap_or_default(),
+            self.af.map(|f| f.to_string()).unwrap_or_default(),
+            self.gene.clone().unwrap_or_default()
+        ]
+    }
+
+    /// Tab separated metadata for output tables (see DESCRIBE_HEADER), NA where unknown
+    pub fn describe(&self) -> String {
+        self.fields()[1..].iter()
+            .map(|f| if f.is_empty() { "NA" } else { f.as_str() })
+            .collect::<Vec<&str>>()
+            .join("\t")
+    }
+
+    /// True if both variants have a known position on the same chromosome within window_bp
+    pub fn is_near(&self, other: &Variant, window_bp: u64) -> bool {
+        match (&self.chrom, self.pos, &other.chrom, other.pos) {
+            (Some(c1), Some(p1), Some(c2), Some(p2)) => c1 == c2 && p1.abs_diff(p2) <= window_bp,
+            _ => false
+        }
+    }
     pub fn set_importance(&mut self, imp: f64) {
         self.max_importance = imp
     }
+}
+
+/// A genomic region, 1-based with both ends inclusive
+pub struct Region {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64
+}
+
+impl Region {
+
+    /// Parse a region written as chr:start-end, or a whole chromosome as chr
+    pub fn parse(region: &str) -> Result<Self, String> {
+        let (chrom, range) = match region.split_once(':') {
+            Some((c, r)) => (c, Some(r)),
+            None => (region, None)
+        };
+        let (start, end) = match range {
+            Some(r) => {
+                let (s, e) = r.split_once('-').ok_or(format!("region {} is not chr:start-end", region))?;
+                let parse = |x: &str| x.replace(',', "").parse::<u64>().map_err(|e| format!("region {}: {}", region, e));
+                (parse(s)?, parse(e)?)
+            },
+            None => (0, u64::MAX)
+        };
+        if chrom.is_empty() || start > end {
+            return Err(format!("region {} is not valid", region))
+        }
+        Ok(Region { chrom: normalize_chrom(chrom), start, end })
+    }
+
+    /// True if the variant has a known position inside the region
+    pub fn contains(&self, v: &Variant) -> bool {
+        match (&v.chrom, v.pos) {
+            (Some(c), Some(p)) => *c == self.chrom && p >= self.start && p <= self.end,
+            _ => false
+        }
+    }
+}
+
+/// Chromosome name without a chr prefix, so chr1 and 1 are the same chromosome
+pub fn normalize_chrom(chrom: &str) -> String {
+    String::from(chrom.strip_prefix("chr").unwrap_or(chrom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chromosome_prefix_is_normalized() {
+        let a = Variant::from_fields(&["a", "chr1", "100"]);
+        let b = Variant::from_fields(&["b", "1", "150"]);
+        assert!(a.is_near(&b, 50));
+        assert!(Region::parse("chr1:1-100").unwrap().contains(&a));
+        assert!(Region::parse("1:120-200").unwrap().contains(&b));
+        assert!(!Region::parse("chr2").unwrap().contains(&a));
+    }
 }
\ No newline at end of file